@@ -0,0 +1,487 @@
+use std::{
+    cell::Cell,
+    env,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{CacheConfig, Size},
+    error,
+    remote::RemoteCache,
+    toolchain::Tool,
+    warning,
+};
+
+const DEFAULT_MAX_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Bumped whenever the key layout changes, so old entries are never reused
+const KEY_VERSION: &str = "cbt-object-cache-1";
+
+/// Compiled objects shared between builds, worktrees and branches, keyed by
+/// the preprocessed source, the command line and the compiler identity
+pub struct ObjectCache {
+    pub dir: PathBuf,
+    pub max_size: u64,
+    pub remote: Option<RemoteCache>,
+    hits: Cell<u64>,
+    remote_hits: Cell<u64>,
+    misses: Cell<u64>,
+    /// Set after the first remote error so a down server only costs one
+    /// timeout per build
+    remote_failed: Cell<bool>,
+}
+
+/// Counters kept in `<dir>/stats` across builds
+#[derive(Deserialize, Serialize, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    #[serde(default)]
+    pub remote_hits: u64,
+    pub misses: u64,
+}
+
+impl ObjectCache {
+    /// The cache described by the config, even when it is disabled, for the
+    /// `cbt cache` commands
+    pub fn open(config: &CacheConfig) -> anyhow::Result<Self> {
+        let dir = match env::var_os("CBT_CACHE_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => match &config.dir {
+                Some(dir) => expand_home(dir)?,
+                None => default_dir()?,
+            },
+        };
+        let max_size = match &config.max_size {
+            Some(size) => size.bytes()?,
+            None => DEFAULT_MAX_SIZE,
+        };
+        let remote = match &config.remote {
+            Some(remote) => {
+                let read_only = match env::var("CBT_REMOTE_CACHE_READ_ONLY") {
+                    Ok(value) => !matches!(value.as_str(), "" | "0" | "false"),
+                    Err(_) => remote.read_only,
+                };
+                Some(RemoteCache::new(&remote.url, read_only)?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            dir,
+            max_size,
+            remote,
+            hits: Cell::new(0),
+            remote_hits: Cell::new(0),
+            misses: Cell::new(0),
+            remote_failed: Cell::new(false),
+        })
+    }
+
+    /// The cache to use while building, if it is enabled
+    pub fn for_build(config: &CacheConfig) -> anyhow::Result<Option<Self>> {
+        if config.enabled {
+            Ok(Some(Self::open(config)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn objects_dir(&self) -> PathBuf {
+        self.dir.join("objects")
+    }
+
+    fn entry(&self, key: &str) -> PathBuf {
+        self.objects_dir().join(&key[..2]).join(key)
+    }
+
+    /// The remote cache, unless it has already failed during this build
+    fn remote(&self) -> Option<&RemoteCache> {
+        if self.remote_failed.get() {
+            None
+        } else {
+            self.remote.as_ref()
+        }
+    }
+
+    fn remote_error(&self, e: anyhow::Error) {
+        println!(
+            "{}: remote cache disabled for this build: {:#}",
+            warning!("Warning"),
+            e
+        );
+        self.remote_failed.set(true);
+    }
+
+    /// Copies the cached object for `key` to `out`, if the local cache or
+    /// the remote one has it
+    pub fn restore(&self, key: &str, out: &Path) -> anyhow::Result<bool> {
+        let entry = self.entry(key);
+        if !entry.exists() {
+            let object = match self.remote().map(|remote| remote.get(key)) {
+                Some(Ok(object)) => object,
+                Some(Err(e)) => {
+                    self.remote_error(e);
+                    None
+                }
+                None => None,
+            };
+            match object {
+                Some(object) => {
+                    self.write_entry(&entry, &object)?;
+                    self.remote_hits.set(self.remote_hits.get() + 1);
+                }
+                None => {
+                    self.misses.set(self.misses.get() + 1);
+                    return Ok(false);
+                }
+            }
+        }
+        fs::copy(&entry, out)
+            .with_context(|| error!("Failed to restore {} from the cache", out.display()))?;
+        // Entries are evicted least recently used first
+        if let Ok(file) = fs::File::options().write(true).open(&entry) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        self.hits.set(self.hits.get() + 1);
+        Ok(true)
+    }
+
+    pub fn store(&self, key: &str, object: &Path) -> anyhow::Result<()> {
+        let contents = fs::read(object)
+            .with_context(|| error!("Failed to store {} in the cache", object.display()))?;
+        self.write_entry(&self.entry(key), &contents)?;
+        if let Some(remote) = self.remote().filter(|remote| !remote.read_only) {
+            if let Err(e) = remote.put(key, &contents) {
+                self.remote_error(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_entry(&self, entry: &Path, contents: &[u8]) -> anyhow::Result<()> {
+        let dir = entry.parent().unwrap_or(&self.dir);
+        fs::create_dir_all(dir)
+            .with_context(|| error!("Failed to create cache directory {}", dir.display()))?;
+        // Write under a temporary name first so concurrent builds never see
+        // a partial object
+        let temp = entry.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&temp, contents).with_context(|| error!("Failed to write {}", temp.display()))?;
+        fs::rename(&temp, entry).with_context(|| error!("Failed to write {}", entry.display()))?;
+        Ok(())
+    }
+
+    /// Hits and misses during this build
+    pub fn session(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.get(),
+            remote_hits: self.remote_hits.get(),
+            misses: self.misses.get(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        fs::read_to_string(self.dir.join("stats"))
+            .ok()
+            .and_then(|stats| toml::from_str(&stats).ok())
+            .unwrap_or_default()
+    }
+
+    /// Adds this build's counters to the totals and trims the cache
+    pub fn finish(&self) -> anyhow::Result<()> {
+        let session = self.session();
+        if session.hits + session.misses == 0 {
+            return Ok(());
+        }
+        let mut stats = self.stats();
+        stats.hits += session.hits;
+        stats.remote_hits += session.remote_hits;
+        stats.misses += session.misses;
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.dir.join("stats"), toml::to_string(&stats)?)
+            .with_context(|| error!("Failed to write cache stats"))?;
+        self.evict()?;
+        Ok(())
+    }
+
+    /// Every entry with its size and last use, oldest first
+    pub fn entries(&self) -> anyhow::Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut entries = Vec::new();
+        let objects_dir = self.objects_dir();
+        if !objects_dir.exists() {
+            return Ok(entries);
+        }
+        for shard in fs::read_dir(&objects_dir)?.flatten() {
+            if !shard.path().is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(shard.path())?.flatten() {
+                let metadata = entry.metadata()?;
+                entries.push((entry.path(), metadata.len(), metadata.modified()?));
+            }
+        }
+        entries.sort_by_key(|(_, _, modified)| *modified);
+        Ok(entries)
+    }
+
+    /// Removes the least recently used objects until the cache is at 90% of
+    /// `max_size`, returning how many were removed
+    pub fn evict(&self) -> anyhow::Result<usize> {
+        let entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        if size <= self.max_size {
+            return Ok(0);
+        }
+        let target = self.max_size / 10 * 9;
+        let mut removed = 0;
+        for (path, entry_size, _) in entries {
+            if size <= target {
+                break;
+            }
+            fs::remove_file(&path).with_context(|| error!("Failed to evict {}", path.display()))?;
+            size -= entry_size;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    pub fn clear(&self) -> anyhow::Result<()> {
+        let objects_dir = self.objects_dir();
+        if objects_dir.exists() {
+            fs::remove_dir_all(&objects_dir)
+                .with_context(|| error!("Failed to remove {}", objects_dir.display()))?;
+        }
+        let stats = self.dir.join("stats");
+        if stats.exists() {
+            fs::remove_file(&stats)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hashes everything that determines the object file. Paths of the source,
+/// output and dependency file, include flags and macro prefix maps are left
+/// out, since their effect is already in the preprocessed source; this lets
+/// worktrees share objects. With debug info the working directory ends up in
+/// the object, so it is part of the key then.
+pub fn cache_key<'a>(
+    tool: &Tool,
+    args: impl Iterator<Item = &'a OsStr>,
+    skip: &[&Path],
+    preprocessed: &[u8],
+) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_VERSION);
+    hasher.update([0]);
+    hasher.update(&tool.program);
+    hasher.update([0]);
+    hasher.update(tool.banner.as_deref().unwrap_or(""));
+    hasher.update([0]);
+    hasher.update(tool.version.as_deref().unwrap_or(""));
+    hasher.update([0]);
+    let mut debug_info = false;
+    for arg in args {
+        let as_path = Path::new(arg);
+        let text = arg.to_string_lossy();
+        if skip.contains(&as_path)
+            || text.starts_with("-I")
+            || text.starts_with("-fmacro-prefix-map=")
+        {
+            continue;
+        }
+        debug_info |= arg.to_string_lossy().starts_with("-g") && arg != "-g0";
+        hasher.update(arg.to_string_lossy().as_bytes());
+        hasher.update([0]);
+    }
+    if debug_info {
+        hasher.update(env::current_dir()?.to_string_lossy().as_bytes());
+        hasher.update([0]);
+    }
+    hasher.update(preprocessed);
+    Ok(hex(&hasher.finalize()))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn default_dir() -> anyhow::Result<PathBuf> {
+    if let Some(dir) = env::var_os("XDG_CACHE_HOME") {
+        return Ok(PathBuf::from(dir).join("cbt"));
+    }
+    Ok(home_dir()?.join(".cache").join("cbt"))
+}
+
+fn home_dir() -> anyhow::Result<PathBuf> {
+    match env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")) {
+        Some(home) => Ok(PathBuf::from(home)),
+        None => bail!(error!(
+            "Could not find the home directory, set [cache] dir or CBT_CACHE_DIR"
+        )),
+    }
+}
+
+fn expand_home(path: &Path) -> anyhow::Result<PathBuf> {
+    match path.strip_prefix("~") {
+        Ok(rest) => Ok(home_dir()?.join(rest)),
+        Err(_) => Ok(path.to_path_buf()),
+    }
+}
+
+/// Formats a byte count for humans, e.g. "1.5 GiB"
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+impl Size {
+    /// `max_size = 1073741824` or `max_size = "1G"`
+    pub fn bytes(&self) -> anyhow::Result<u64> {
+        let text = match self {
+            Size::Bytes(bytes) => return Ok(*bytes),
+            Size::Text(text) => text.trim(),
+        };
+        let split = text
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+        let multiplier: u64 = match unit.trim().to_ascii_uppercase().trim_end_matches("IB") {
+            "" | "B" => 1,
+            "K" | "KB" => 1024,
+            "M" | "MB" => 1024 * 1024,
+            "G" | "GB" => 1024 * 1024 * 1024,
+            "T" | "TB" => 1024 * 1024 * 1024 * 1024,
+            _ => bail!(error!("Unknown size unit in {}", text)),
+        };
+        match number.parse::<f64>() {
+            Ok(number) => Ok((number * multiplier as f64) as u64),
+            Err(_) => bail!(error!("Invalid size {}", text)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toolchain::Family;
+    use std::time::Duration;
+
+    #[test]
+    fn sizes_in_bytes() {
+        for (size, bytes) in [
+            (Size::Bytes(1000), 1000),
+            (Size::Text("512".to_owned()), 512),
+            (Size::Text("10B".to_owned()), 10),
+            (Size::Text("2K".to_owned()), 2048),
+            (Size::Text("1.5 MiB".to_owned()), 1536 * 1024),
+            (Size::Text("5G".to_owned()), 5 << 30),
+            (Size::Text(" 1tb ".to_owned()), 1 << 40),
+        ] {
+            assert_eq!(size.bytes().unwrap(), bytes);
+        }
+        assert!(Size::Text("5X".to_owned()).bytes().is_err());
+        assert!(Size::Text("G".to_owned()).bytes().is_err());
+    }
+
+    fn key(args: &[&str], skip: &[&Path], preprocessed: &str) -> String {
+        let tool = Tool {
+            program: "gcc".to_owned(),
+            family: Family::Gcc,
+            version: Some("13.2.0".to_owned()),
+            banner: Some("gcc (GCC) 13.2.0".to_owned()),
+        };
+        cache_key(
+            &tool,
+            args.iter().map(OsStr::new),
+            skip,
+            preprocessed.as_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn cache_keys_ignore_paths() {
+        let base = key(
+            &[
+                "-c",
+                "/a/src/main.c",
+                "-o",
+                "/a/build/main.o",
+                "-I/a/include",
+                "-fmacro-prefix-map=/a=.",
+            ],
+            &[Path::new("/a/src/main.c"), Path::new("/a/build/main.o")],
+            "int main;",
+        );
+        let worktree = key(
+            &[
+                "-c",
+                "/b/src/main.c",
+                "-o",
+                "/b/build/main.o",
+                "-I/b/include",
+                "-fmacro-prefix-map=/b=.",
+            ],
+            &[Path::new("/b/src/main.c"), Path::new("/b/build/main.o")],
+            "int main;",
+        );
+        assert_eq!(base, worktree);
+        assert_eq!(base.len(), 64);
+
+        assert_ne!(base, key(&["-c", "-O2"], &[], "int main;"));
+        assert_ne!(
+            key(&["-O2"], &[], "int main;"),
+            key(&["-O3"], &[], "int main;")
+        );
+        assert_ne!(key(&[], &[], "int main;"), key(&[], &[], "int other;"));
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let dir = env::temp_dir().join(format!("cbt-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = ObjectCache::open(&CacheConfig {
+            enabled: true,
+            dir: Some(dir.clone()),
+            max_size: Some(Size::Bytes(150)),
+            remote: None,
+        })
+        .unwrap();
+        let now = SystemTime::now();
+        for (key, age) in [("aa01", 30), ("bb02", 10), ("cc03", 20)] {
+            let entry = cache.entry(key);
+            cache.write_entry(&entry, &[0; 100]).unwrap();
+            let file = fs::File::options().write(true).open(&entry).unwrap();
+            file.set_modified(now - Duration::from_secs(age)).unwrap();
+        }
+        let order: Vec<_> = cache
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|(path, _, _)| path.file_name().unwrap().to_owned())
+            .collect();
+        assert_eq!(order, ["aa01", "cc03", "bb02"]);
+
+        // 300 bytes is over the limit, and the oldest two go to get under 135
+        assert_eq!(cache.evict().unwrap(), 2);
+        assert!(!cache.entry("aa01").exists());
+        assert!(!cache.entry("cc03").exists());
+        assert!(cache.entry("bb02").exists());
+        assert_eq!(cache.evict().unwrap(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{bail, Context};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    compilation::define_args,
+    config::{Check, CheckKind, Define, Stage},
+    db::fingerprint,
+    error,
+    files::get_dirs,
+    generate::identifier,
+    message,
+    toolchain::{library_args, Toolchain},
+};
+
+/// Results of earlier checks per stage, since stages can share a build
+/// directory. They are keyed by the check and the compile command so changed
+/// flags run them again.
+#[derive(Deserialize, Serialize, Default)]
+struct CheckCache {
+    #[serde(default)]
+    stages: BTreeMap<String, BTreeMap<String, CheckResult>>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CheckResult {
+    /// The macro body, or none if the check failed
+    value: Option<String>,
+}
+
+/// Sizes are searched up to this, far beyond any real type
+const MAX_SIZEOF: u64 = 1 << 32;
+
+pub fn cache_path(build_dir: &Path) -> PathBuf {
+    build_dir.join(".cbt").join("checks")
+}
+
+/// Compiles probe programs with a stage's C compiler and flags
+struct Probe<'a> {
+    compiler: &'a str,
+    args: Vec<String>,
+    link_args: Vec<String>,
+    dir: PathBuf,
+}
+
+impl Probe<'_> {
+    fn compiles(&self, source: &str) -> anyhow::Result<bool> {
+        self.run(source, &["-c"], "probe.o", &[])
+    }
+
+    fn links(&self, source: &str) -> anyhow::Result<bool> {
+        self.run(source, &[], "probe", &self.link_args)
+    }
+
+    fn run(
+        &self,
+        source: &str,
+        mode: &[&str],
+        output: &str,
+        extra_args: &[String],
+    ) -> anyhow::Result<bool> {
+        let probe = self.dir.join("probe.c");
+        fs::write(&probe, source).with_context(|| error!("Failed to write {}", probe.display()))?;
+        let status = Command::new(self.compiler)
+            .args(mode)
+            .arg(&probe)
+            .arg("-o")
+            .arg(self.dir.join(output))
+            .args(&self.args)
+            .args(extra_args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .with_context(|| error!("Failed to spawn {} process", self.compiler))?;
+        Ok(status.success())
+    }
+}
+
+/// Runs `checks` with `stage`'s compiler and flags, reusing the results
+/// cached in its build directory, and returns the defines they produce. Failed
+/// checks leave their macro undefined.
+pub fn run_checks(
+    checks: &[Check],
+    toolchain: &Toolchain,
+    stage: &Stage,
+) -> anyhow::Result<BTreeMap<String, Define>> {
+    let mut defines = BTreeMap::new();
+    if checks.is_empty() {
+        return Ok(defines);
+    }
+    let (_, build_dir) = get_dirs(stage)?;
+    let dir = build_dir.join(".cbt").join("probe");
+    fs::create_dir_all(&dir).with_context(|| error!("Failed to create {}", dir.display()))?;
+
+    let compiler = &toolchain.compilers.cc;
+    let tool = toolchain.tool(compiler, "cc")?;
+    let mut args = toolchain.target_args(tool.family);
+    args.extend(stage.flags.cflags.iter().cloned());
+    for include in &stage.includes.include_dirs {
+        args.push(format!("-I{}", include.display().to_string().trim()));
+    }
+    args.extend(define_args(&stage.defines));
+    // Probes are deliberately sloppy C, which -Werror in cflags mustn't fail
+    args.push("-w".to_owned());
+    let probe = Probe {
+        compiler,
+        args,
+        link_args: library_args(&stage.libraries, toolchain.linker_flavor(tool.family))?,
+        dir,
+    };
+
+    let path = cache_path(&build_dir);
+    let mut cache: CheckCache = fs::read_to_string(&path)
+        .ok()
+        .and_then(|cache| toml::from_str(&cache).ok())
+        .unwrap_or_default();
+    let cached_results = cache.stages.remove(&stage.name).unwrap_or_default();
+    // Only keep the results of checks that still exist
+    let mut results = BTreeMap::new();
+    for check in checks {
+        let (define, description) = match &check.kind {
+            CheckKind::Header(header) => (format!("HAVE_{}", upper(header)), header.clone()),
+            CheckKind::Function(function) => (
+                format!("HAVE_{}", upper(function)),
+                format!("{}()", function),
+            ),
+            CheckKind::Sizeof(ty) => (sizeof_define(ty), format!("sizeof({})", ty)),
+            CheckKind::Compiles(_) => match &check.define {
+                Some(define) => (define.clone(), define.clone()),
+                None => bail!(error!("A compiles check needs a define")),
+            },
+        };
+        let define = check.define.clone().unwrap_or(define);
+
+        let (kind, subject) = match &check.kind {
+            CheckKind::Header(subject) => ("header", subject),
+            CheckKind::Function(subject) => ("function", subject),
+            CheckKind::Sizeof(subject) => ("sizeof", subject),
+            CheckKind::Compiles(subject) => ("compiles", subject),
+        };
+        let key = fingerprint(
+            OsStr::new(compiler),
+            probe
+                .args
+                .iter()
+                .chain(&probe.link_args)
+                .chain(&check.includes)
+                .map(OsStr::new)
+                .chain([OsStr::new(kind), OsStr::new(subject)]),
+        );
+        let (value, cached) = match cached_results.get(&key) {
+            Some(result) => (result.value.clone(), true),
+            None => (run_check(&probe, check)?, false),
+        };
+        println!(
+            "{} {}: {}{}",
+            message!("Checking"),
+            description,
+            match &value {
+                Some(value) if matches!(check.kind, CheckKind::Sizeof(_)) => value.as_str(),
+                Some(_) => "yes",
+                None => "no",
+            },
+            if cached { " (cached)" } else { "" }
+        );
+        defines.insert(
+            define,
+            match &value {
+                Some(value) => Define::Raw { raw: value.clone() },
+                None => Define::Bool(false),
+            },
+        );
+        results.insert(key, CheckResult { value });
+    }
+
+    cache.stages.insert(stage.name.clone(), results);
+    fs::write(&path, toml::to_string(&cache)?)
+        .with_context(|| error!("Failed to write {}", path.display()))?;
+    Ok(defines)
+}
+
+/// The macro body `check` defines, or `None` if it failed
+fn run_check(probe: &Probe, check: &Check) -> anyhow::Result<Option<String>> {
+    let includes: String = check
+        .includes
+        .iter()
+        .map(|header| format!("#include <{}>\n", header))
+        .collect();
+    let passed = match &check.kind {
+        CheckKind::Header(header) => probe.compiles(&format!(
+            "{}#include <{}>\nint main(void) {{ return 0; }}\n",
+            includes, header
+        ))?,
+        // Without headers declare it like autoconf, so any prototype links
+        CheckKind::Function(function) if includes.is_empty() => probe.links(&format!(
+            "char {}(void);\nint main(void) {{ return {}(); }}\n",
+            function, function
+        ))?,
+        CheckKind::Function(function) => probe.links(&format!(
+            "{}int main(void) {{ void (*volatile f)(void) = (void (*)(void))&{}; return f != 0; }}\n",
+            includes, function
+        ))?,
+        CheckKind::Sizeof(ty) => {
+            let fits = |size: u64| {
+                probe.compiles(&format!(
+                    "{}typedef char probe[(sizeof({}) <= {}ULL) ? 1 : -1];\nint main(void) {{ return 0; }}\n",
+                    includes, ty, size
+                ))
+            };
+            // Only compiled, never run, so it also works when cross compiling
+            let exists = probe.compiles(&format!(
+                "{}typedef char probe[sizeof({}) ? 1 : -1];\nint main(void) {{ return 0; }}\n",
+                includes, ty
+            ))?;
+            if !exists {
+                return Ok(None);
+            }
+            let (mut low, mut high) = (1, 1024);
+            while !fits(high)? {
+                if high >= MAX_SIZEOF {
+                    bail!(error!(
+                        "sizeof({}) is over {} bytes, too large to check",
+                        ty,
+                        MAX_SIZEOF
+                    ));
+                }
+                low = high + 1;
+                high *= 2;
+            }
+            while low < high {
+                let middle = (low + high) / 2;
+                if fits(middle)? {
+                    high = middle;
+                } else {
+                    low = middle + 1;
+                }
+            }
+            return Ok(Some(low.to_string()));
+        }
+        CheckKind::Compiles(snippet) => probe.compiles(&format!("{}{}\n", includes, snippet))?,
+    };
+    Ok(passed.then(|| "1".to_owned()))
+}
+
+fn upper(name: &str) -> String {
+    identifier(name.trim()).to_uppercase()
+}
+
+/// `SIZEOF_<TYPE>` as autoconf spells it, with `*` as `P` and runs of
+/// spaces or underscores collapsed, e.g. `SIZEOF_VOID_P` for `void *`
+fn sizeof_define(ty: &str) -> String {
+    let name = upper(&ty.replace('*', "p"));
+    let mut define = String::from("SIZEOF");
+    for part in name.split('_').filter(|part| !part.is_empty()) {
+        define.push('_');
+        define.push_str(part);
+    }
+    define
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{test_stage, Compilers};
+
+    #[test]
+    fn sizeof_define_names() {
+        assert_eq!(sizeof_define("int"), "SIZEOF_INT");
+        assert_eq!(sizeof_define("void *"), "SIZEOF_VOID_P");
+        assert_eq!(sizeof_define("void*"), "SIZEOF_VOIDP");
+        assert_eq!(sizeof_define("char **"), "SIZEOF_CHAR_PP");
+        assert_eq!(
+            sizeof_define("unsigned  long long"),
+            "SIZEOF_UNSIGNED_LONG_LONG"
+        );
+        assert_eq!(sizeof_define("struct __foo"), "SIZEOF_STRUCT_FOO");
+    }
+
+    fn stage(name: &str, build_dir: &Path) -> Stage {
+        let mut stage = test_stage(name);
+        stage.build.build_dir = build_dir.to_path_buf();
+        stage
+    }
+
+    fn check(kind: CheckKind) -> Check {
+        Check {
+            kind,
+            define: None,
+            includes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn checks_of_stages_sharing_a_build_dir() {
+        let build_dir = std::env::temp_dir().join(format!("cbt-checks-{}", std::process::id()));
+        let toolchain = Toolchain::new(Compilers::default());
+        let checks = [
+            check(CheckKind::Header("stdio.h".to_owned())),
+            check(CheckKind::Header("no/such/header.h".to_owned())),
+            check(CheckKind::Sizeof("char[5000]".to_owned())),
+        ];
+        let app = stage("app", &build_dir);
+        let mut lib = stage("lib", &build_dir);
+        lib.flags.cflags.push("-DLIB".to_owned());
+
+        let defines = run_checks(&checks, &toolchain, &app).unwrap();
+        assert_eq!(defines["HAVE_STDIO_H"].value().as_deref(), Some("1"));
+        assert_eq!(defines["HAVE_NO_SUCH_HEADER_H"].value(), None);
+        assert_eq!(defines["SIZEOF_CHAR_5000"].value().as_deref(), Some("5000"));
+        run_checks(&checks[..1], &toolchain, &lib).unwrap();
+
+        let cache: CheckCache =
+            toml::from_str(&fs::read_to_string(cache_path(&build_dir)).unwrap()).unwrap();
+        assert_eq!(cache.stages["app"].len(), 3);
+        assert_eq!(cache.stages["lib"].len(), 1);
+        fs::remove_dir_all(&build_dir).unwrap();
+    }
+}
@@ -41,6 +41,176 @@ pub enum Commands {
     Build {
         #[arg(short, long)]
         config: Option<PathBuf>,
+        /// Profile to apply on top of every stage, defaults to "debug" if defined
+        #[arg(short, long)]
+        profile: Option<String>,
+        /// Cross compile for a target triple defined under [target.<triple>]
+        #[arg(short, long)]
+        target: Option<String>,
+        /// Toolchain file to merge over [compilers]
+        #[arg(long)]
+        toolchain: Option<PathBuf>,
+        /// Add or override a preprocessor define, as NAME or NAME=VALUE
+        #[arg(short = 'D', long = "define", value_name = "NAME[=VALUE]")]
+        defines: Vec<String>,
+    },
+
+    // Toolchain
+    #[command(bin_name = "toolchain")]
+    #[command(author, about = "Show the detected compilers and tools")]
+    #[command(help_template = "\
+{name} {version}
+
+{about}
+
+{usage-heading}
+  {usage}
+
+{all-args}
+{author-section}
+    ")]
+    Toolchain {
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+        #[arg(short, long)]
+        target: Option<String>,
+        #[arg(long)]
+        toolchain: Option<PathBuf>,
+    },
+
+    // Cache
+    #[command(bin_name = "cache")]
+    #[command(author, about = "Inspect or clear the object cache")]
+    #[command(help_template = "\
+{name} {version}
+
+{about}
+
+{usage-heading}
+  {usage}
+
+{all-args}
+{author-section}
+    ")]
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+
+    // Explain
+    #[command(bin_name = "explain")]
+    #[command(author, about = "Show when and why outputs were last built")]
+    #[command(help_template = "\
+{name} {version}
+
+{about}
+
+{usage-heading}
+  {usage}
+
+{all-args}
+{author-section}
+    ")]
+    Explain {
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+        #[arg(short, long)]
+        target: Option<String>,
+        /// Only show outputs whose path ends with this
+        output: Option<PathBuf>,
+    },
+
+    // Install
+    #[command(bin_name = "install")]
+    #[command(author, about = "Install the built artifacts and headers")]
+    #[command(help_template = "\
+{name} {version}
+
+{about}
+
+{usage-heading}
+  {usage}
+
+{all-args}
+{author-section}
+    ")]
+    Install {
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+        /// The profile the artifacts were built with
+        #[arg(short, long)]
+        profile: Option<String>,
+        #[arg(short, long)]
+        target: Option<String>,
+        /// The toolchain file the artifacts were built with
+        #[arg(long)]
+        toolchain: Option<PathBuf>,
+        /// The defines the artifacts were built with, as NAME or NAME=VALUE
+        #[arg(short = 'D', long = "define", value_name = "NAME[=VALUE]")]
+        defines: Vec<String>,
+        /// Installs into bin/, lib/ and include/ under this; DESTDIR is prepended
+        #[arg(long, default_value = "/usr/local")]
+        prefix: PathBuf,
+    },
+
+    // Uninstall
+    #[command(bin_name = "uninstall")]
+    #[command(author, about = "Remove everything the last install put in place")]
+    #[command(help_template = "\
+{name} {version}
+
+{about}
+
+{usage-heading}
+  {usage}
+
+{all-args}
+{author-section}
+    ")]
+    Uninstall {
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+    },
+
+    // Dist
+    #[command(bin_name = "dist")]
+    #[command(author, about = "Pack the project sources into name-version.tar.gz")]
+    #[command(help_template = "\
+{name} {version}
+
+{about}
+
+{usage-heading}
+  {usage}
+
+{all-args}
+{author-section}
+    ")]
+    Dist {
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+    },
+
+    // Distcheck
+    #[command(bin_name = "distcheck")]
+    #[command(
+        author,
+        about = "Pack the project sources and check that they build on their own"
+    )]
+    #[command(help_template = "\
+{name} {version}
+
+{about}
+
+{usage-heading}
+  {usage}
+
+{all-args}
+{author-section}
+    ")]
+    Distcheck {
+        #[arg(short, long)]
+        config: Option<PathBuf>,
     },
 
     // Clean
@@ -57,7 +227,12 @@ pub enum Commands {
 {all-args}
 {author-section}
     ")]
-    Clean,
+    Clean {
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+        #[arg(short, long)]
+        target: Option<String>,
+    },
 
     // Gen config
     #[command(bin_name = "gen-config")]
@@ -95,12 +270,37 @@ pub enum Commands {
     GenCompletions { shell: Shell },
 }
 
+#[derive(Subcommand, Debug)]
+pub enum CacheAction {
+    #[command(about = "Show the cache location, size and hit rate")]
+    Stats {
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+    },
+    #[command(about = "Remove every cached object")]
+    Clear {
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+    },
+    #[command(about = "Run a local stand-in for a bazel-remote cache server")]
+    Serve {
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+        /// Where to keep blobs, defaults to "server" in the cache directory
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+        #[arg(short, long, default_value = "127.0.0.1:8080")]
+        bind: String,
+    },
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum Shell {
     Bash,
     Fish,
     Zsh,
-    PowerShell,
+    #[value(name = "power-shell")]
+    Powershell,
     Elvish,
 }
 
@@ -110,7 +310,7 @@ impl Shell {
             Shell::Bash => "bash",
             Shell::Fish => "fish",
             Shell::Zsh => "zsh",
-            Shell::PowerShell => "ps1",
+            Shell::Powershell => "ps1",
             Shell::Elvish => "elv",
         }
     }
@@ -2,20 +2,32 @@ use std::{
     env::{self, set_current_dir},
     fs,
     path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use clap::CommandFactory;
 use clap_complete::generate;
 
 use crate::{
+    bold,
+    cache::{human_size, ObjectCache},
+    checks,
     cli::{Cli, Shell},
     compilation::run_stage,
-    config::{load_config, Config},
-    error, warning,
+    config::{load_config, load_toolchain_file, Assembler, Config, Define, Target},
+    db::{output_files, BuildDb},
+    dist, error,
+    files::get_dirs,
+    generate::generated_dir,
+    info, install, message, pkgconfig, remote,
+    toolchain::Toolchain,
+    warning,
 };
 
-pub fn build(config_path: Option<PathBuf>) -> anyhow::Result<()> {
+/// Loads the config and moves into its directory, which every path in it is
+/// relative to
+fn open_project(config_path: Option<PathBuf>) -> anyhow::Result<Config> {
     let config_path = if let Some(config_path) = config_path {
         config_path
     } else {
@@ -27,8 +39,7 @@ pub fn build(config_path: Option<PathBuf>) -> anyhow::Result<()> {
             config_path.display()
         )
     })?;
-    let config = load_config(&config_path)?;
-    let compilers = &config.compilers;
+    let mut config = load_config(&config_path)?;
     let config_dir = match config_path.parent() {
         Some(dir) => dir.to_path_buf(),
         None => {
@@ -40,15 +51,555 @@ pub fn build(config_path: Option<PathBuf>) -> anyhow::Result<()> {
         }
     };
 
-    set_current_dir(config_dir).with_context(|| error!("{}", "Could not set current directory"))?;
+    set_current_dir(&config_dir)
+        .with_context(|| error!("{}", "Could not set current directory"))?;
+    config.dir = config_dir;
+    Ok(config)
+}
+
+pub fn build(
+    config_path: Option<PathBuf>,
+    profile: Option<String>,
+    target: Option<String>,
+    toolchain_file: Option<PathBuf>,
+    defines: Vec<String>,
+) -> anyhow::Result<()> {
+    let toolchain_file = absolute_path(toolchain_file)?;
+    let mut config = open_project(config_path)?;
+    apply_profile(&mut config, profile)?;
+    apply_defines(&mut config, &defines);
+
+    let toolchain = resolve_toolchain(&mut config, toolchain_file, target)?;
+    apply_dependencies(&mut config, &toolchain)?;
+    for stage in &mut config.stages {
+        stage.check_defines = checks::run_checks(&config.checks, &toolchain, stage)?;
+        stage.defines.extend(stage.check_defines.clone());
+    }
+    if config.project.name.is_some()
+        || !config.configure_files.is_empty()
+        || !config.embeds.is_empty()
+    {
+        // For the version and config headers, configured files and embeds
+        for stage in &mut config.stages {
+            let generated_dir = generated_dir(&stage.build.build_dir, stage);
+            stage.includes.include_dirs.push(generated_dir);
+        }
+    }
+
+    // cc is always needed to create executables, so check for it up front
+    toolchain.tool(&toolchain.compilers.cc, "cc")?;
+
+    let cache = ObjectCache::for_build(&config.cache)?;
+    let launcher_stats = toolchain.launcher_stats();
+    for stage in &config.stages {
+        run_stage(&config, &toolchain, cache.as_ref(), stage)?;
+    }
+
+    if let Some(cache) = &cache {
+        let session = cache.session();
+        if session.hits + session.misses > 0 {
+            println!(
+                "{}: {} hits ({} remote), {} misses",
+                info!("Object cache"),
+                session.hits,
+                session.remote_hits,
+                session.misses
+            );
+        }
+        cache.finish()?;
+    }
+
+    if let (Some(launcher), Some(before), Some(after)) = (
+        toolchain.launcher(),
+        launcher_stats,
+        toolchain.launcher_stats(),
+    ) {
+        let hits = after.hits.saturating_sub(before.hits);
+        let misses = after.misses.saturating_sub(before.misses);
+        if hits + misses > 0 {
+            println!(
+                "{}: {} hits, {} misses ({:.0}% hit rate)",
+                info!("{}", launcher),
+                hits,
+                misses,
+                hits as f64 * 100.0 / (hits + misses) as f64
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn toolchain(
+    config_path: Option<PathBuf>,
+    target: Option<String>,
+    toolchain_file: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let toolchain_file = absolute_path(toolchain_file)?;
+    let mut config = open_project(config_path)?;
+    let toolchain = resolve_toolchain(&mut config, toolchain_file, target)?;
+    let compilers = &toolchain.compilers;
+
+    let mut tools = vec![("cc", compilers.cc.clone()), ("cxx", compilers.cxx.clone())];
+    for stage in &config.stages {
+        if stage.assembler != Assembler::Cc {
+            let program = stage.assembler.program(compilers);
+            if !tools.iter().any(|(_, tool)| *tool == program) {
+                tools.push(("asm", program));
+            }
+        }
+    }
+    tools.push(("linker", compilers.linker.clone()));
+    tools.push(("archiver", compilers.archiver.clone()));
+    tools.push(("objcopy", compilers.objcopy.clone()));
+
+    for (role, program) in tools {
+        match toolchain.tool(&program, role) {
+            Ok(tool) => {
+                let version = match &tool.version {
+                    Some(version) => format!("{} {}", tool.family, version),
+                    None => tool.family.to_string(),
+                };
+                println!(
+                    "{:<10}{} ({})",
+                    info!("{}", role),
+                    bold!("{}", tool.program),
+                    version
+                );
+                if let Some(banner) = &tool.banner {
+                    println!("{:<10}{}", "", banner);
+                }
+            }
+            Err(_) => println!(
+                "{:<10}{} {}",
+                info!("{}", role),
+                bold!("{}", program),
+                error!("not found")
+            ),
+        }
+    }
+    if let Some(sysroot) = &toolchain.sysroot {
+        println!("{:<10}{}", info!("sysroot"), sysroot.display());
+    }
+    if let Some(linker_script) = &toolchain.linker_script {
+        println!("{:<10}{}", info!("ldscript"), linker_script.display());
+    }
+    Ok(())
+}
 
+/// Every build directory the stages use, as `build` would for `target`
+fn build_dirs(config: &Config, target: Option<&str>) -> anyhow::Result<Vec<PathBuf>> {
+    let mut build_dirs = Vec::new();
     for stage in &config.stages {
-        run_stage(compilers, stage)?;
+        let (_, mut build_dir) = get_dirs(stage)?;
+        if let Some(target) = target {
+            build_dir = build_dir.join(target);
+        }
+        if !build_dirs.contains(&build_dir) {
+            build_dirs.push(build_dir);
+        }
+    }
+    Ok(build_dirs)
+}
+
+pub fn explain(
+    config_path: Option<PathBuf>,
+    target: Option<String>,
+    output: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let config = open_project(config_path)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+    let mut found = false;
+    for build_dir in build_dirs(&config, target.as_deref())? {
+        let mut db = BuildDb::load(&build_dir)?;
+        let outputs: Vec<PathBuf> = db
+            .outputs
+            .keys()
+            .filter(|path| output.as_ref().is_none_or(|output| path.ends_with(output)))
+            .cloned()
+            .collect();
+        for path in outputs {
+            found = true;
+            let record = &db.outputs[&path];
+            let staleness = config
+                .stages
+                .iter()
+                .find(|stage| stage.name == record.stage)
+                .map(|stage| stage.build.staleness)
+                .unwrap_or_default();
+            let (stage, built_at, reason) =
+                (record.stage.clone(), record.built_at, record.reason.clone());
+            let inputs = record.inputs.len();
+            let headers = record.headers.len();
+
+            println!("{}", bold!("{}", path.display()));
+            if !stage.is_empty() {
+                println!("  {:<10}{}", info!("Stage"), stage);
+            }
+            println!(
+                "  {:<10}{} ago, because {}",
+                info!("Built"),
+                ago(now.saturating_sub(built_at)),
+                reason
+            );
+            println!("  {:<10}{} ({} headers)", info!("Inputs"), inputs, headers);
+            // Only a build knows the command, so changed flags don't show here
+            match db.changed_input(&path, staleness)? {
+                Some(reason) => println!("  {:<10}stale, {}", info!("Now"), reason),
+                None => println!("  {:<10}inputs unchanged", info!("Now")),
+            }
+        }
+    }
+    if !found {
+        match output {
+            Some(output) => bail!(error!("No recorded output matches {}", output.display())),
+            None => println!("{}: nothing has been built yet", info!("Explain")),
+        }
+    }
+    Ok(())
+}
+
+fn ago(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+/// Removes every output recorded in the build database, with the dependency
+/// files of objects, then the database itself
+pub fn clean(config_path: Option<PathBuf>, target: Option<String>) -> anyhow::Result<()> {
+    let config = open_project(config_path)?;
+    for build_dir in build_dirs(&config, target.as_deref())? {
+        let db = BuildDb::load(&build_dir)?;
+        let mut removed = 0;
+        for output in db.outputs.keys() {
+            for file in output_files(output) {
+                if file.is_file() {
+                    fs::remove_file(&file)
+                        .with_context(|| error!("Failed to remove {}", file.display()))?;
+                    removed += 1;
+                }
+            }
+        }
+        for path in [BuildDb::path(&build_dir), checks::cache_path(&build_dir)] {
+            if path.exists() {
+                fs::remove_file(&path)
+                    .with_context(|| error!("Failed to remove {}", path.display()))?;
+            }
+        }
+        println!(
+            "{} {} files from {}",
+            message!("Removed"),
+            removed,
+            build_dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// Takes the same options as `build`, so the installed `.pc` files and
+/// paths match the artifacts that were built
+pub fn install(
+    config_path: Option<PathBuf>,
+    profile: Option<String>,
+    target: Option<String>,
+    toolchain_file: Option<PathBuf>,
+    defines: Vec<String>,
+    prefix: PathBuf,
+) -> anyhow::Result<()> {
+    // Relative to where cbt was run, not the project
+    let destdir = match env::var_os("DESTDIR") {
+        Some(destdir) if !destdir.is_empty() => Some(env::current_dir()?.join(destdir)),
+        _ => None,
+    };
+    let toolchain_file = absolute_path(toolchain_file)?;
+    let mut config = open_project(config_path)?;
+    apply_profile(&mut config, profile)?;
+    apply_defines(&mut config, &defines);
+    let toolchain = resolve_toolchain(&mut config, toolchain_file, target)?;
+    install::install(&config, &toolchain, &prefix, destdir.as_deref())
+}
+
+pub fn uninstall(config_path: Option<PathBuf>) -> anyhow::Result<()> {
+    let config = open_project(config_path)?;
+    install::uninstall(&config.dir)
+}
+
+/// The config file's name, which is its path inside the tarball
+fn config_file_name(config_path: &Option<PathBuf>) -> PathBuf {
+    config_path
+        .as_deref()
+        .and_then(|path| path.file_name())
+        .map(PathBuf::from)
+        .unwrap_or(PathBuf::from("cbt.toml"))
+}
+
+pub fn dist(config_path: Option<PathBuf>) -> anyhow::Result<()> {
+    let config_file = config_file_name(&config_path);
+    let config = open_project(config_path)?;
+    dist::dist(&config, &config_file)?;
+    Ok(())
+}
+
+pub fn distcheck(config_path: Option<PathBuf>) -> anyhow::Result<()> {
+    let config_file = config_file_name(&config_path);
+    let config = open_project(config_path)?;
+    let tarball = dist::dist(&config, &config_file)?;
+    dist::distcheck(&config, &config_file, &tarball)
+}
+
+/// Loads the config if there is one, since the cache is shared between
+/// projects and `cbt cache` should work anywhere
+fn cache_for(config_path: Option<PathBuf>) -> anyhow::Result<ObjectCache> {
+    let config_path = config_path.unwrap_or(PathBuf::from("cbt.toml"));
+    if config_path.exists() {
+        let config = open_project(Some(config_path))?;
+        ObjectCache::open(&config.cache)
+    } else {
+        ObjectCache::open(&Default::default())
+    }
+}
+
+pub fn cache_stats(config_path: Option<PathBuf>) -> anyhow::Result<()> {
+    let cache = cache_for(config_path)?;
+    let entries = cache.entries()?;
+    let size = entries.iter().map(|(_, size, _)| size).sum();
+    let stats = cache.stats();
+
+    println!("{:<10}{}", info!("Location"), cache.dir.display());
+    println!("{:<10}{}", info!("Objects"), entries.len());
+    println!(
+        "{:<10}{} of {}",
+        info!("Size"),
+        human_size(size),
+        human_size(cache.max_size)
+    );
+    if let Some(remote) = &cache.remote {
+        println!(
+            "{:<10}{}{}",
+            info!("Remote"),
+            remote.url,
+            if remote.read_only { " (read-only)" } else { "" }
+        );
+    }
+    println!(
+        "{:<10}{} ({} remote)",
+        info!("Hits"),
+        stats.hits,
+        stats.remote_hits
+    );
+    println!("{:<10}{}", info!("Misses"), stats.misses);
+    if stats.hits + stats.misses > 0 {
+        println!(
+            "{:<10}{:.0}%",
+            info!("Hit rate"),
+            stats.hits as f64 * 100.0 / (stats.hits + stats.misses) as f64
+        );
+    }
+    Ok(())
+}
+
+pub fn cache_clear(config_path: Option<PathBuf>) -> anyhow::Result<()> {
+    let cache = cache_for(config_path)?;
+    cache.clear()?;
+    println!("{} {}", message!("Cleared"), cache.dir.display());
+    Ok(())
+}
+
+pub fn cache_serve(
+    config_path: Option<PathBuf>,
+    dir: Option<PathBuf>,
+    bind: String,
+) -> anyhow::Result<()> {
+    if let Some(dir) = &dir {
+        fs::create_dir_all(dir).with_context(|| error!("Failed to create {}", dir.display()))?;
+    }
+    let dir = absolute_path(dir)?;
+    let cache = cache_for(config_path)?;
+    let dir = dir.unwrap_or(cache.dir.join("server"));
+    fs::create_dir_all(&dir).with_context(|| error!("Failed to create {}", dir.display()))?;
+    remote::serve(&dir, &bind)
+}
+
+/// Resolves a path given on the command line before `open_project` changes
+/// the current directory
+fn absolute_path(path: Option<PathBuf>) -> anyhow::Result<Option<PathBuf>> {
+    match path {
+        Some(path) => {
+            Ok(Some(path.canonicalize().with_context(|| {
+                error!("Could not find {}", path.display())
+            })?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Resolves every stage's `[stage.dependencies]` with pkg-config and adds
+/// their flags to the stage
+fn apply_dependencies(config: &mut Config, toolchain: &Toolchain) -> anyhow::Result<()> {
+    for stage in &mut config.stages {
+        for (name, dependency) in &stage.dependencies {
+            let package = pkgconfig::resolve(
+                name,
+                &dependency.pkg_config,
+                toolchain.sysroot.as_deref(),
+                toolchain.target.as_deref(),
+            )?;
+            println!("{} {} {}", message!("Found"), name, package.version);
+            stage.flags.cflags.extend(package.cflags.iter().cloned());
+            stage.flags.cxxflags.extend(package.cflags);
+            stage.libraries.dependency_args.extend(package.libs);
+        }
+    }
+    Ok(())
+}
+
+/// Applies the toolchain file (`--toolchain` over `toolchain = ...` in the
+/// config), then the `--target` section
+fn resolve_toolchain(
+    config: &mut Config,
+    toolchain_file: Option<PathBuf>,
+    target: Option<String>,
+) -> anyhow::Result<Toolchain> {
+    let mut toolchain = Toolchain::new(config.compilers.clone());
+    let definition = match toolchain_file.or(config.toolchain.clone()) {
+        Some(path) => Some(load_toolchain_file(&path)?),
+        None => None,
+    };
+    if let Some(definition) = &definition {
+        apply_toolchain(config, &mut toolchain, definition);
+    }
+    if let Some(triple) = target {
+        apply_target(config, &mut toolchain, &triple, definition.as_ref())?;
+    }
+    Ok(toolchain)
+}
+
+/// Merges a toolchain definition over the config. Its compilers replace the
+/// ones from `[compilers]`, and its flags go before each stage's own flags.
+fn apply_toolchain(config: &mut Config, toolchain: &mut Toolchain, definition: &Target) {
+    let compilers = &mut config.compilers;
+    let fields = [
+        (&mut compilers.cc, &definition.cc),
+        (&mut compilers.cxx, &definition.cxx),
+        (&mut compilers.asm, &definition.asm),
+        (&mut compilers.linker, &definition.linker),
+        (&mut compilers.archiver, &definition.archiver),
+        (&mut compilers.objcopy, &definition.objcopy),
+    ];
+    for (field, value) in fields {
+        if let Some(value) = value {
+            *field = value.clone();
+        }
+    }
+    for stage in &mut config.stages {
+        let flags = &mut stage.flags;
+        let defaults = &definition.flags;
+        flags.cflags.splice(0..0, defaults.cflags.iter().cloned());
+        flags
+            .cxxflags
+            .splice(0..0, defaults.cxxflags.iter().cloned());
+        flags
+            .asmflags
+            .splice(0..0, defaults.asmflags.iter().cloned());
+        flags.ldflags.splice(0..0, defaults.ldflags.iter().cloned());
+    }
+
+    toolchain.compilers = config.compilers.clone();
+    if definition.sysroot.is_some() {
+        toolchain.sysroot = definition.sysroot.clone();
     }
+    if definition.linker_script.is_some() {
+        toolchain.linker_script = definition.linker_script.clone();
+    }
+}
+
+/// Switches the config over to a `[target.<triple>]` section. Outputs go to
+/// `build_dir/<triple>/` and `target_dir/<triple>/` so host and cross builds
+/// can coexist. Tools the toolchain file didn't set default to the GNU cross
+/// names.
+fn apply_target(
+    config: &mut Config,
+    toolchain: &mut Toolchain,
+    triple: &str,
+    toolchain_file: Option<&Target>,
+) -> anyhow::Result<()> {
+    let target = match config.targets.remove(triple) {
+        Some(target) => target,
+        None => bail!(error!(
+            "Target {} is not defined, add a [target.{}] section to the config",
+            triple, triple
+        )),
+    };
+    let defaults = Target::default();
+    let file = toolchain_file.unwrap_or(&defaults);
+    let compilers = &mut config.compilers;
+    let tools = [
+        (&mut compilers.cc, &file.cc, "gcc"),
+        (&mut compilers.cxx, &file.cxx, "g++"),
+        (&mut compilers.linker, &file.linker, "ld"),
+        (&mut compilers.archiver, &file.archiver, "ar"),
+        (&mut compilers.objcopy, &file.objcopy, "objcopy"),
+    ];
+    for (field, from_file, tool) in tools {
+        if from_file.is_none() {
+            *field = format!("{}-{}", triple, tool);
+        }
+    }
+    for stage in &mut config.stages {
+        stage.build.build_dir = stage.build.build_dir.join(triple);
+        // The target dir is only used when it exists
+        if let Some(target_dir) = &mut stage.build.target_dir {
+            if target_dir.is_dir() {
+                *target_dir = target_dir.join(triple);
+                fs::create_dir_all(&target_dir)
+                    .with_context(|| error!("Failed to create {}", target_dir.display()))?;
+            }
+        }
+    }
+    apply_toolchain(config, toolchain, &target);
+    toolchain.target = Some(triple.to_owned());
+    Ok(())
+}
 
+/// Layers the selected profile over every stage. Without `--profile`, the
+/// "debug" profile is used when the config has one.
+fn apply_profile(config: &mut Config, profile: Option<String>) -> anyhow::Result<()> {
+    let (name, profile) = match profile {
+        Some(name) => match config.profiles.get(&name) {
+            Some(profile) => (name, profile),
+            None => bail!(error!("Profile {} is not defined in the config", name)),
+        },
+        None => match config.profiles.get("debug") {
+            Some(profile) => ("debug".to_owned(), profile),
+            None => return Ok(()),
+        },
+    };
+    for stage in &mut config.stages {
+        stage.settings.merge(&profile.settings);
+        stage
+            .defines
+            .extend(profile.defines.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    println!("{} {} profile", message!("Using"), name);
+    config.profile = Some(name);
     Ok(())
 }
 
+/// Adds `--define` arguments to every stage, over the config's own
+fn apply_defines(config: &mut Config, defines: &[String]) {
+    let defines: Vec<(String, Define)> = defines.iter().map(|arg| Define::parse_arg(arg)).collect();
+    for stage in &mut config.stages {
+        stage.defines.extend(defines.iter().cloned());
+    }
+}
+
 pub fn gen_config(path: Option<PathBuf>) -> anyhow::Result<()> {
     let path = if let Some(path) = path {
         path
@@ -107,7 +658,7 @@ pub fn gen_completions(shell: Shell) -> anyhow::Result<()> {
                 &mut out_file,
             );
         }
-        Shell::PowerShell => {
+        Shell::Powershell => {
             generate(
                 clap_complete::shells::Bash,
                 &mut app,
@@ -127,3 +678,118 @@ pub fn gen_completions(shell: Shell) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_stage;
+
+    fn cross_config(target_dir: &std::path::Path) -> Config {
+        let mut config: Config = toml::from_str(
+            r#"
+            stage = []
+
+            [target.aarch64-linux-gnu]
+            cxx = "aarch64-linux-gnu-clang++"
+            "#,
+        )
+        .unwrap();
+        config.stages.push(test_stage(""));
+        config.stages[0].build.target_dir = Some(target_dir.to_path_buf());
+        config
+    }
+
+    #[test]
+    fn targets_use_cross_tools_and_their_own_dirs() {
+        let target_dir = env::temp_dir().join(format!("cbt-target-{}", std::process::id()));
+        fs::create_dir_all(&target_dir).unwrap();
+        let mut config = cross_config(&target_dir);
+
+        let toolchain =
+            resolve_toolchain(&mut config, None, Some("aarch64-linux-gnu".to_owned())).unwrap();
+        let compilers = &toolchain.compilers;
+        assert_eq!(compilers.cc, "aarch64-linux-gnu-gcc");
+        assert_eq!(compilers.cxx, "aarch64-linux-gnu-clang++");
+        assert_eq!(compilers.linker, "aarch64-linux-gnu-ld");
+        assert_eq!(compilers.archiver, "aarch64-linux-gnu-ar");
+        assert_eq!(compilers.objcopy, "aarch64-linux-gnu-objcopy");
+        assert_eq!(toolchain.target.as_deref(), Some("aarch64-linux-gnu"));
+
+        let build = &config.stages[0].build;
+        assert_eq!(
+            build.build_dir,
+            PathBuf::from("build").join("aarch64-linux-gnu")
+        );
+        let target_dir = target_dir.join("aarch64-linux-gnu");
+        assert_eq!(build.target_dir.as_ref(), Some(&target_dir));
+        assert!(target_dir.is_dir());
+        fs::remove_dir_all(target_dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn missing_target_dirs_are_left_alone() {
+        let target_dir = env::temp_dir().join(format!("cbt-no-target-{}", std::process::id()));
+        let mut config = cross_config(&target_dir);
+        resolve_toolchain(&mut config, None, Some("aarch64-linux-gnu".to_owned())).unwrap();
+        assert_eq!(
+            config.stages[0].build.target_dir.as_ref(),
+            Some(&target_dir)
+        );
+        assert!(!target_dir.exists());
+
+        let mut config = cross_config(&target_dir);
+        assert!(
+            resolve_toolchain(&mut config, None, Some("riscv64-linux-gnu".to_owned())).is_err()
+        );
+    }
+
+    #[test]
+    fn toolchain_precedence() {
+        let dir = env::temp_dir().join(format!("cbt-precedence-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let from_config = dir.join("config.toml");
+        let from_cli = dir.join("cli.toml");
+        fs::write(
+            &from_config,
+            "cc = \"clang\"\ncxx = \"clang++\"\nflags = { cflags = [\"-DCONFIG\"] }\n",
+        )
+        .unwrap();
+        fs::write(
+            &from_cli,
+            "cc = \"tcc\"\nsysroot = \"root\"\nflags = { cflags = [\"-DCLI\"] }\n",
+        )
+        .unwrap();
+        let config = || {
+            let mut config = cross_config(&dir.join("target"));
+            config.compilers.cc = "gcc".to_owned();
+            config.compilers.cxx = "g++".to_owned();
+            config.stages[0].flags.cflags.push("-DSTAGE".to_owned());
+            config.toolchain = Some(from_config.clone());
+            config
+        };
+
+        // The toolchain file in cbt.toml replaces [compilers]
+        let mut plain = config();
+        let toolchain = resolve_toolchain(&mut plain, None, None).unwrap();
+        assert_eq!(toolchain.compilers.cc, "clang");
+        assert_eq!(toolchain.compilers.cxx, "clang++");
+        assert_eq!(plain.stages[0].flags.cflags, ["-DCONFIG", "-DSTAGE"]);
+
+        // --toolchain replaces the one in cbt.toml
+        let mut cli = config();
+        let toolchain = resolve_toolchain(&mut cli, Some(from_cli.clone()), None).unwrap();
+        assert_eq!(toolchain.compilers.cc, "tcc");
+        assert_eq!(toolchain.compilers.cxx, "g++");
+        assert_eq!(toolchain.sysroot, Some(dir.join("root")));
+        assert_eq!(cli.stages[0].flags.cflags, ["-DCLI", "-DSTAGE"]);
+
+        // The file's tools win over the cross names, the target section over both
+        let mut cross = config();
+        let toolchain =
+            resolve_toolchain(&mut cross, None, Some("aarch64-linux-gnu".to_owned())).unwrap();
+        assert_eq!(toolchain.compilers.cc, "clang");
+        assert_eq!(toolchain.compilers.cxx, "aarch64-linux-gnu-clang++");
+        assert_eq!(toolchain.compilers.linker, "aarch64-linux-gnu-ld");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,62 +1,166 @@
-use std::{path::PathBuf, process::Command};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    ffi::OsStr,
+    fs,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+};
 
 use crate::{
     bold,
-    config::{Compilers, Stage},
+    cache::{cache_key, ObjectCache},
+    config::{Assembler, Compilers, Config, Define, LibraryKind, LinkMode, Project, Stage},
+    db::{fingerprint, output_files, BuildDb, Step},
     error,
-    files::{get_dirs, get_src_files, setup_build_dir, Language, SourceFile},
-    info, message,
-    util::process_output,
+    files::{get_dirs, get_src_files, prune_dir_structure, setup_build_dir, Language, SourceFile},
+    generate::{
+        config_header, configure, configure_variables, embed_header, embed_source, generated_dir,
+        stage_embeds, version_header,
+    },
+    info, message, pkgconfig,
+    toolchain::{library_args, settings_flags, Family, Toolchain},
+    util::{process_output, read_depfile},
+    warning,
 };
 use anyhow::{bail, Context};
 use run_script::ScriptOptions;
 
-pub fn compile(file: &SourceFile, compilers: &Compilers, stage: &Stage) -> anyhow::Result<PathBuf> {
-    let (compiler, flags, out_extension) = match file.lang {
-        Language::C => (&compilers.cc, &stage.flags.cflags, "o"),
-        Language::CXX => (&compilers.cxx, &stage.flags.cxxflags, "o"),
-        Language::ASM => (&compilers.asm, &stage.flags.asmflags, "asm.o"),
-        //_ => bail!("Unsupported language"),
+pub fn compile(
+    file: &SourceFile,
+    toolchain: &Toolchain,
+    cache: Option<&ObjectCache>,
+    db: &mut BuildDb,
+    stage: &Stage,
+) -> anyhow::Result<PathBuf> {
+    let compilers = &toolchain.compilers;
+    let assembler = match file.lang {
+        Language::Asm => stage.assembler,
+        _ => Assembler::Cc,
     };
+    let (compiler, flags) = match file.lang {
+        Language::C => (compilers.cc.clone(), &stage.flags.cflags),
+        Language::Cxx => (compilers.cxx.clone(), &stage.flags.cxxflags),
+        Language::Asm => (assembler.program(compilers), &stage.flags.asmflags),
+        // Preprocessed assembly goes through the C driver, so it takes cflags
+        Language::AsmCpp => (assembler.program(compilers), &stage.flags.cflags),
+        Language::Header => bail!(error!("Cannot compile header {}", file.name)),
+    };
+    let compiler = &file.compiler.clone().unwrap_or(compiler);
+    let tool = toolchain.tool(compiler, file.lang.compiler_role(assembler))?;
 
-    let out_file = file.out_path.with_extension(out_extension);
-    if is_up_to_date(&out_file, &file.path)? {
-        println!(
-            "{}: {} is up to date",
-            info!("Skipping compile step"),
-            bold!("{}", out_file.display())
-        );
-        return Ok(out_file);
-    }
-    println!(
-        "{} {} to {}",
-        message!("Compiling"),
-        file.name,
-        out_file.display()
-    );
+    let out_file = file.object_path();
+    let dep_file = out_file.with_extension("d");
 
-    let includes = match file.lang {
-        Language::C | Language::CXX => {
+    // Spawn compiler process. nasm, yasm and gas aren't supported by launchers.
+    let mut cmd = match assembler {
+        Assembler::Cc => toolchain.compile_command(compiler),
+        _ => Command::new(compiler),
+    };
+    match file.lang {
+        Language::Asm | Language::AsmCpp => {
+            assembler.args(&mut cmd, &file.path, &out_file, &dep_file, stage, toolchain);
+            if assembler == Assembler::Cc {
+                cmd.args(toolchain.target_args(tool.family));
+            }
+            cmd.args(flags).args(&file.flags);
+        }
+        _ => {
             let mut includes = Vec::new();
             for include in &stage.includes.include_dirs {
                 includes.push(format!("-I{}", include.display().to_string().trim()));
             }
-            includes
+            if tool.family == Family::Msvc {
+                cmd.arg("/c")
+                    .arg(&file.path)
+                    .arg(format!("/Fo{}", out_file.display()));
+            } else {
+                cmd.arg("-c").arg(&file.path).arg("-o").arg(&out_file);
+                // Headers are tracked through the dependency file
+                cmd.arg("-MD").arg("-MF").arg(&dep_file);
+                if stage.build.library == Some(LibraryKind::Shared) {
+                    cmd.arg("-fPIC");
+                }
+                // __FILE__ would put absolute paths into the preprocessed
+                // source and keep worktrees from sharing cached objects
+                if cache.is_some() && matches!(tool.family, Family::Gcc | Family::Clang) {
+                    cmd.arg(format!(
+                        "-fmacro-prefix-map={}=.",
+                        env::current_dir()?.display()
+                    ));
+                }
+            }
+            cmd.args(includes)
+                .args(define_args(&stage.defines))
+                .args(settings_flags(tool.family, file.lang, &stage.settings))
+                .args(toolchain.target_args(tool.family))
+                .args(flags)
+                .args(&file.flags);
+        }
+    }
+
+    let command = fingerprint(OsStr::new(compiler), command_args(&cmd, compiler));
+    let step = Step {
+        stage: &stage.name,
+        output: &out_file,
+        inputs: std::slice::from_ref(&file.path),
+        command: &command,
+        exact: false,
+    };
+    let reason = match db.stale_reason(&step, stage.build.staleness)? {
+        Some(reason) => reason,
+        None => {
+            println!(
+                "{}: {} is up to date",
+                info!("Skipping compile step"),
+                bold!("{}", out_file.display())
+            );
+            return Ok(out_file);
+        }
+    };
+
+    // Only cc-style drivers can preprocess for the cache key
+    let cache_key = match (cache, assembler) {
+        (Some(_), Assembler::Cc) if tool.family != Family::Msvc => {
+            // The compile arguments include -MD, so preprocessing also writes
+            // the dependency file a restored object is recorded with. Without
+            // it the object is compiled instead.
+            if dep_file.exists() {
+                fs::remove_file(&dep_file)
+                    .with_context(|| error!("Failed to remove {}", dep_file.display()))?;
+            }
+            let preprocessed = preprocess(&cmd, compiler)?;
+            match preprocessed {
+                Some(preprocessed) if dep_file.exists() => Some(cache_key(
+                    &tool,
+                    command_args(&cmd, compiler),
+                    &[&file.path, &out_file, &dep_file],
+                    &preprocessed,
+                )?),
+                _ => None,
+            }
         }
-        _ => Vec::new(),
+        _ => None,
     };
+    if let (Some(cache), Some(key)) = (cache, &cache_key) {
+        if cache.restore(key, &out_file)? {
+            println!(
+                "{} {} from cache to {}",
+                message!("Restoring"),
+                file.name,
+                out_file.display()
+            );
+            db.record(&step, &read_depfile(&dep_file)?, &reason)?;
+            return Ok(out_file);
+        }
+    }
 
-    // Spawn compiler process
-    let mut cmd = Command::new(compiler);
-    cmd.arg(match file.lang {
-        Language::ASM => "",
-        _ => "-c",
-    })
-    .arg(&file.path)
-    .arg("-o")
-    .arg(&out_file)
-    .args(includes)
-    .args(flags);
+    println!(
+        "{} {} to {}",
+        message!("Compiling"),
+        file.name,
+        out_file.display()
+    );
     //println!("{:?}", cmd);
     let compiler_process = cmd
         .spawn()
@@ -67,63 +171,250 @@ pub fn compile(file: &SourceFile, compilers: &Compilers, stage: &Stage) -> anyho
         .with_context(|| error!("Failed to get {} output", compiler))?;
 
     process_output(output, compiler, &file.name, "compile")?;
+    if let (Some(cache), Some(key)) = (cache, &cache_key) {
+        cache.store(key, &out_file)?;
+    }
+
+    if assembler == Assembler::Yasm && file.lang == Language::Asm {
+        // yasm can only print dependencies to stdout, in a separate pass
+        let mut cmd = Command::new(compiler);
+        cmd.arg("-M")
+            .args(assembler.include_args(stage))
+            .args(assembler.define_args(&stage.defines))
+            .args(flags)
+            .args(&file.flags)
+            .arg(&file.path);
+        let output = cmd
+            .output()
+            .with_context(|| error!("Failed to spawn {} process", compiler))?;
+        let stdout = output.stdout.clone();
+        process_output(output, compiler, &file.name, "list the dependencies of")?;
+        fs::write(&dep_file, &stdout)
+            .with_context(|| error!("Failed to write {}", dep_file.display()))?;
+    }
+    db.record(&step, &depfile_headers(&dep_file)?, &reason)?;
     Ok(out_file)
 }
 
+/// The files listed in a make-style dependency file, if the compiler wrote
+/// one
+fn depfile_headers(dep_file: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if dep_file.exists() {
+        read_depfile(dep_file)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// The compiler's arguments, without the launcher in front
+fn command_args<'a>(cmd: &'a Command, compiler: &str) -> impl Iterator<Item = &'a OsStr> {
+    let launched = cmd.get_program() != OsStr::new(compiler);
+    cmd.get_args().skip(usize::from(launched))
+}
+
+/// Runs the compile command with `-E` instead of `-c`, for the object cache
+/// key. Line markers are dropped with `-P` unless there is debug info, which
+/// records the line numbers. Returns `None` if preprocessing fails, so the
+/// compile itself reports the error.
+fn preprocess(cmd: &Command, compiler: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let debug_info = command_args(cmd, compiler)
+        .any(|arg| arg.to_string_lossy().starts_with("-g") && arg != "-g0");
+    let mut preprocess = Command::new(compiler);
+    let mut args = command_args(cmd, compiler);
+    while let Some(arg) = args.next() {
+        if arg == "-c" {
+            preprocess.arg("-E");
+            if !debug_info {
+                preprocess.arg("-P");
+            }
+        } else if arg == "-o" {
+            args.next();
+        } else {
+            preprocess.arg(arg);
+        }
+    }
+    let output = preprocess
+        .stderr(Stdio::null())
+        .output()
+        .with_context(|| error!("Failed to spawn {} process", compiler))?;
+    if output.status.success() {
+        Ok(Some(output.stdout))
+    } else {
+        Ok(None)
+    }
+}
+
+impl Assembler {
+    /// The program to run. `compilers.asm` is only used for the dialect it
+    /// names, or for any dialect if cbt can't tell which one it is, so stages
+    /// with different dialects each get their usual assembler.
+    pub fn program(&self, compilers: &Compilers) -> String {
+        let default = match self {
+            Assembler::Cc => return compilers.cc.clone(),
+            Assembler::Nasm => "nasm",
+            Assembler::Yasm => "yasm",
+            Assembler::Gas => "as",
+        };
+        match Assembler::from_program(&compilers.asm) {
+            _ if compilers.asm.is_empty() => default.to_owned(),
+            Some(dialect) if dialect != *self => default.to_owned(),
+            _ => compilers.asm.clone(),
+        }
+    }
+
+    /// The dialect an assembler program speaks, judged by its name, e.g.
+    /// `x86_64-linux-gnu-as` is gas
+    fn from_program(program: &str) -> Option<Assembler> {
+        let name = Path::new(program)
+            .file_stem()?
+            .to_str()?
+            .to_ascii_lowercase();
+        match name.rsplit('-').next()? {
+            "nasm" => Some(Assembler::Nasm),
+            "yasm" => Some(Assembler::Yasm),
+            "as" | "gas" => Some(Assembler::Gas),
+            _ => None,
+        }
+    }
+
+    fn include_args(&self, stage: &Stage) -> Vec<String> {
+        let mut includes = Vec::new();
+        for include in &stage.includes.include_dirs {
+            let include = include.display().to_string().trim().to_owned();
+            match self {
+                // nasm concatenates the prefix and the %include name as-is
+                Assembler::Nasm if !include.ends_with('/') => {
+                    includes.push(format!("-I{}/", include))
+                }
+                _ => includes.push(format!("-I{}", include)),
+            }
+        }
+        includes
+    }
+
+    /// gas only supports numeric symbols through `--defsym`, so other
+    /// defines are skipped for it
+    fn define_args(&self, defines: &BTreeMap<String, Define>) -> Vec<String> {
+        match self {
+            Assembler::Gas => defines
+                .iter()
+                .filter_map(|(name, define)| {
+                    let value = define.value()?.parse::<i64>().ok()?;
+                    Some(format!("--defsym={}={}", name, value))
+                })
+                .collect(),
+            _ => define_args(defines),
+        }
+    }
+
+    /// Adds everything except the user's flags to an assembler command
+    fn args(
+        &self,
+        cmd: &mut Command,
+        src: &Path,
+        out: &Path,
+        dep_file: &Path,
+        stage: &Stage,
+        toolchain: &Toolchain,
+    ) {
+        let has_format = stage
+            .flags
+            .asmflags
+            .iter()
+            .any(|flag| flag.starts_with("-f") || flag.starts_with("--oformat"));
+        match self {
+            Assembler::Nasm | Assembler::Yasm if !has_format => {
+                cmd.arg(format!("-f{}", toolchain.object_format()));
+            }
+            _ => (),
+        }
+        match self {
+            Assembler::Cc => {
+                cmd.arg("-c").arg("-MD").arg("-MF").arg(dep_file);
+                if stage.build.library == Some(LibraryKind::Shared) {
+                    cmd.arg("-fPIC");
+                }
+            }
+            Assembler::Nasm => {
+                cmd.arg("-MD").arg(dep_file).arg("-MT").arg(out);
+            }
+            Assembler::Gas => {
+                cmd.arg("--MD").arg(dep_file);
+            }
+            Assembler::Yasm => (),
+        }
+        cmd.args(self.include_args(stage))
+            .args(self.define_args(&stage.defines))
+            .arg("-o")
+            .arg(out)
+            .arg(src);
+    }
+}
+
+/// Turns defines into `-DNAME=value` arguments for cc-style drivers
+pub fn define_args(defines: &BTreeMap<String, Define>) -> Vec<String> {
+    defines
+        .iter()
+        .filter_map(|(name, define)| Some(format!("-D{}={}", name, define.value()?)))
+        .collect()
+}
+
 pub fn compile_src_files(
-    src_files: &Vec<SourceFile>,
-    compilers: &Compilers,
+    src_files: &[SourceFile],
+    toolchain: &Toolchain,
+    cache: Option<&ObjectCache>,
+    db: &mut BuildDb,
     stage: &Stage,
 ) -> anyhow::Result<Vec<PathBuf>> {
     let mut out_files = Vec::new();
 
-    for file in src_files {
-        let out_file = compile(file, &compilers, &stage)?;
+    for file in src_files.iter().filter(|file| file.lang.is_compiled()) {
+        let out_file = compile(file, toolchain, cache, db, stage)?;
         out_files.push(out_file);
     }
     Ok(out_files)
 }
 
 pub fn link_object_files(
-    obj_files: &Vec<PathBuf>,
-    build_dir: &PathBuf,
-    compilers: &Compilers,
+    obj_files: &[PathBuf],
+    build_dir: &Path,
+    toolchain: &Toolchain,
+    db: &mut BuildDb,
     stage: &Stage,
 ) -> anyhow::Result<PathBuf> {
+    let compilers = &toolchain.compilers;
     // Link object files
-    let out_name = match &stage.build.executable {
-        Some(name) => name.to_owned(),
-        None => "full_project_out".to_owned(),
-    };
-    let out_file = build_dir.join(&out_name).with_extension("o");
+    let out_file = linked_object_path(build_dir, stage);
+    let out_name = out_file.display().to_string();
 
     println!("{} {}", message!("Linking objects to"), out_file.display());
 
-    let mut up_to_date = false;
-    for obj in obj_files {
-        if is_up_to_date(&out_file, obj)? {
-            up_to_date = true;
-        } else {
-            up_to_date = false;
-            break;
-        }
-    }
-    if up_to_date {
-        println!(
-            "{}: {} is up to date",
-            info!("Skipping link step"),
-            bold!("{}", out_file.file_name().unwrap().to_str().unwrap())
-        );
-        return Ok(out_file);
-    }
-
     let mut cmd = Command::new(&compilers.linker);
-    cmd
-        //.arg("-relocatable")
+    cmd.arg("-r")
         .args(obj_files)
         .arg("-o")
         .arg(&out_file)
         .args(&stage.flags.ldflags);
+
+    let command = fingerprint(cmd.get_program(), cmd.get_args());
+    let step = Step {
+        stage: &stage.name,
+        output: &out_file,
+        inputs: obj_files,
+        command: &command,
+        exact: true,
+    };
+    let reason = match db.stale_reason(&step, stage.build.staleness)? {
+        Some(reason) => reason,
+        None => {
+            println!(
+                "{}: {} is up to date",
+                info!("Skipping link step"),
+                bold!("{}", out_file.file_name().unwrap().to_str().unwrap())
+            );
+            return Ok(out_file);
+        }
+    };
     //println!("{:?}", cmd);
 
     let child = cmd
@@ -137,34 +428,34 @@ pub fn link_object_files(
         )
     })?;
     process_output(output, &compilers.linker, &out_name, "link")?;
+    db.record(&step, &[], &reason)?;
     Ok(out_file)
 }
 
-fn is_up_to_date(target: &PathBuf, source: &PathBuf) -> anyhow::Result<bool> {
-    if target.exists() && source.exists() {
-        Ok(target
-            .metadata()
-            .with_context(|| error!("Could not read metadata from {}", target.display()))?
-            .created()
-            .with_context(|| error!("Could not read metadata from {}", target.display()))?
-            >= source
-                .metadata()
-                .with_context(|| error!("Could not read metadata from {}", source.display()))?
-                .modified()
-                .with_context(|| error!("Could not read metadata from {}", source.display()))?)
-    } else {
-        Ok(false)
-    }
+/// Where `link_object_files` puts the single object it links everything into
+fn linked_object_path(build_dir: &Path, stage: &Stage) -> PathBuf {
+    let out_name = match &stage.build.executable {
+        Some(name) => name.to_owned(),
+        None => "full_project_out".to_owned(),
+    };
+    build_dir.join(out_name).with_extension("o")
 }
 
-pub fn create_executable(
+/// `build.executable`, else the project name, else `a.out`
+pub fn executable_name<'a>(project: &'a Project, stage: &'a Stage) -> &'a str {
+    stage
+        .build
+        .executable
+        .as_deref()
+        .or(project.name.as_deref())
+        .unwrap_or("a.out")
+}
+
+pub fn executable_path(
     executable_name: &str,
-    obj_file: &PathBuf,
-    build_dir: &PathBuf,
-    compilers: &Compilers,
+    build_dir: &Path,
     stage: &Stage,
-) -> anyhow::Result<()> {
-    // Compile object file
+) -> anyhow::Result<PathBuf> {
     let executable_dir = if let Some(target_dir) = &stage.build.target_dir {
         if target_dir.canonicalize()?.exists() {
             target_dir
@@ -174,39 +465,244 @@ pub fn create_executable(
     } else {
         build_dir
     };
-    let executable_path = executable_dir.join(executable_name);
+    Ok(executable_dir.join(executable_name))
+}
 
-    if is_up_to_date(&executable_path, obj_file)? {
-        println!(
-            "{}: {} is up to date",
-            info!("Skipping executable step"),
-            bold!("{}", executable_path.file_name().unwrap().to_str().unwrap())
-        );
-        return Ok(());
+/// `lib<stage name>.a` or `.so`, where the executable would go otherwise
+pub fn library_path(
+    kind: LibraryKind,
+    build_dir: &Path,
+    toolchain: &Toolchain,
+    stage: &Stage,
+) -> anyhow::Result<PathBuf> {
+    if stage.name.is_empty() {
+        bail!(error!(
+            "Library stages need a name, which is used for the library"
+        ));
     }
+    let file_name = match kind {
+        LibraryKind::Static => format!("lib{}.a", stage.name),
+        LibraryKind::Shared => {
+            format!("lib{}.{}", stage.name, toolchain.shared_library_extension())
+        }
+    };
+    executable_path(&file_name, build_dir, stage)
+}
 
-    println!(
-        "{} {}",
-        message!("Creating executable"),
-        executable_path.display()
-    );
+/// The installed and uninstalled `.pc` files for a library stage, which
+/// pkg-config finds with `PKG_CONFIG_PATH=<build_dir>/pkgconfig`
+fn pc_paths(build_dir: &Path, stage: &Stage) -> [PathBuf; 2] {
+    let dir = build_dir.join("pkgconfig");
+    [
+        dir.join(format!("lib{}.pc", stage.name)),
+        dir.join(format!("lib{}-uninstalled.pc", stage.name)),
+    ]
+}
+
+/// Fails if two steps of the stage would write the same file, e.g. `foo.c`
+/// and `Foo.c` on a case-insensitive filesystem, or an executable named like
+/// an object
+fn check_output_collisions(
+    src_files: &[SourceFile],
+    build_dir: &Path,
+    toolchain: &Toolchain,
+    project: &Project,
+    stage: &Stage,
+) -> anyhow::Result<()> {
+    let current_dir = env::current_dir()?;
+    let mut outputs: Vec<(PathBuf, String)> = src_files
+        .iter()
+        .filter(|file| file.lang.is_compiled())
+        .map(|file| (file.object_path(), file.path.display().to_string()))
+        .collect();
+    if stage.build.link == LinkMode::Relocatable {
+        outputs.push((
+            linked_object_path(build_dir, stage),
+            "the linked object".to_owned(),
+        ));
+    }
+    if let Some(kind) = stage.build.library {
+        outputs.push((
+            library_path(kind, build_dir, toolchain, stage)?,
+            "the library".to_owned(),
+        ));
+        for pc_path in pc_paths(build_dir, stage) {
+            outputs.push((pc_path, "the pkg-config file".to_owned()));
+        }
+    } else if stage.build.build_executable {
+        outputs.push((
+            executable_path(executable_name(project, stage), build_dir, stage)?,
+            "the executable".to_owned(),
+        ));
+    }
+
+    let outputs: Vec<(PathBuf, String)> = outputs
+        .into_iter()
+        .map(|(output, producer)| (current_dir.join(output), producer))
+        .collect();
+    if let Some((first, second, output)) = find_collision(&outputs, case_insensitive(build_dir)) {
+        bail!(error!(
+            "{} and {} would both be written to {}",
+            first,
+            second,
+            output.display()
+        ));
+    }
+    Ok(())
+}
+
+/// The first two producers writing the same output, comparing paths without
+/// case if `fold_case`
+fn find_collision(outputs: &[(PathBuf, String)], fold_case: bool) -> Option<(&str, &str, &Path)> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    for (output, producer) in outputs {
+        let mut key = output.to_string_lossy().into_owned();
+        if fold_case {
+            key = key.to_lowercase();
+        }
+        if let Some(other) = seen.insert(key, producer) {
+            return Some((other, producer, output));
+        }
+    }
+    None
+}
+
+/// Whether `dir` is on a case-insensitive filesystem, found by creating a
+/// file and looking it up with different case. Falls back to the platform's
+/// usual default if `dir` isn't writable.
+fn case_insensitive(dir: &Path) -> bool {
+    let probe = dir.join(".cbt-Case-Probe");
+    if fs::write(&probe, "").is_err() {
+        return cfg!(any(windows, target_os = "macos"));
+    }
+    let insensitive = dir.join(".cbt-case-probe").exists();
+    let _ = fs::remove_file(&probe);
+    insensitive
+}
+
+/// ldflags are for the linker itself, so they are passed through the driver
+/// with `-Wl,` unless they already are. A relocatable link in them would
+/// leave an object file where the executable should be.
+fn ldflag_args(ldflags: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    for flag in ldflags {
+        let linker_flags = flag.strip_prefix("-Wl,").unwrap_or(flag);
+        if linker_flags
+            .split(',')
+            .any(|flag| matches!(flag, "-r" | "-relocatable" | "--relocatable"))
+        {
+            bail!(error!(
+                "ldflags contains {}, set link = \"relocatable\" in [stage.build] instead",
+                flag
+            ));
+        }
+        if flag.starts_with("-Wl,") {
+            args.push(flag.clone());
+        } else {
+            args.push(format!("-Wl,{}", flag));
+        }
+    }
+    Ok(args)
+}
+
+/// Links `obj_files` into an executable, or a shared library if `shared`,
+/// with the C++ driver if `cxx`, so its runtime is linked in, otherwise with
+/// the C driver
+pub fn create_executable(
+    executable_path: &Path,
+    obj_files: &[PathBuf],
+    cxx: bool,
+    shared: bool,
+    toolchain: &Toolchain,
+    db: &mut BuildDb,
+    stage: &Stage,
+) -> anyhow::Result<()> {
+    let compilers = &toolchain.compilers;
+    let what = if shared { "library" } else { "executable" };
+
+    let (driver, role, flags) = if cxx {
+        (&compilers.cxx, "cxx", &stage.flags.cxxflags)
+    } else {
+        (&compilers.cc, "cc", &stage.flags.cflags)
+    };
     let exe_flags = match stage.build.executable_extra_flags {
-        Some(ref flags) => {
-            let mut temp = stage.flags.cflags.clone();
-            temp.extend(flags.clone());
+        Some(ref extra_flags) => {
+            let mut temp = flags.clone();
+            temp.extend(extra_flags.clone());
             temp
         }
-        None => stage.flags.cflags.clone(),
+        None => flags.clone(),
     };
     let mut includes = Vec::new();
     for include in &stage.includes.include_dirs {
         includes.push(format!("-I{}", include.display().to_string().trim()));
     }
-    let mut cmd = Command::new(&compilers.cc);
-    cmd.arg(&obj_file)
-        .arg("-o")
-        .arg(&executable_path)
+    let tool = toolchain.tool(driver, role)?;
+    let mut cmd = Command::new(driver);
+    cmd.args(obj_files);
+    if shared {
+        cmd.arg("-shared");
+        if let Some(file_name) = executable_path.file_name() {
+            match toolchain.shared_library_extension() {
+                "so" => cmd.arg(format!("-Wl,-soname,{}", file_name.to_string_lossy())),
+                "dylib" => cmd.arg(format!(
+                    "-Wl,-install_name,@rpath/{}",
+                    file_name.to_string_lossy()
+                )),
+                _ => &mut cmd,
+            };
+        }
+    }
+    cmd.arg("-o")
+        .arg(executable_path)
+        .args(toolchain.target_args(tool.family))
         .args(&exe_flags);
+    // The relocatable link already applied the ldflags
+    if stage.build.link == LinkMode::Direct {
+        cmd.args(ldflag_args(&stage.flags.ldflags)?);
+    }
+    if let Some(linker_script) = &toolchain.linker_script {
+        cmd.arg("-T").arg(linker_script);
+    }
+    cmd.args(library_args(
+        &stage.libraries,
+        toolchain.linker_flavor(tool.family),
+    )?);
+
+    // Relink when a library given by path is rebuilt
+    let mut inputs = obj_files.to_vec();
+    let libraries = &stage.libraries;
+    for library in libraries.link_whole.iter().chain(&libraries.libs) {
+        if let Some(path) = library.path().filter(|path| path.exists()) {
+            inputs.push(path.to_path_buf());
+        }
+    }
+
+    let command = fingerprint(cmd.get_program(), cmd.get_args());
+    let step = Step {
+        stage: &stage.name,
+        output: executable_path,
+        inputs: &inputs,
+        command: &command,
+        exact: true,
+    };
+    let reason = match db.stale_reason(&step, stage.build.staleness)? {
+        Some(reason) => reason,
+        None => {
+            println!(
+                "{}: {} is up to date",
+                info!("Skipping {} step", what),
+                bold!("{}", executable_path.file_name().unwrap().to_str().unwrap())
+            );
+            return Ok(());
+        }
+    };
+
+    println!(
+        "{} {}",
+        message!("Creating {}", what),
+        executable_path.display()
+    );
     //println!("{:?}", cmd);
     let child = cmd
         //.args(includes)
@@ -216,59 +712,482 @@ pub fn create_executable(
     let output = child.wait_with_output().with_context(|| {
         error!(
             "Failed to wait for {} compiler process to complete compilation of {}",
-            &compilers.cc,
+            driver,
             &executable_path.display()
         )
     })?;
+    let objects = match obj_files {
+        [obj_file] => obj_file.display().to_string(),
+        _ => format!("{} objects", obj_files.len()),
+    };
     process_output(
         output,
-        &compilers.cc,
-        &obj_file.display().to_string(),
-        format!("create executable {} from", executable_path.display()).as_str(),
+        driver,
+        &objects,
+        format!("create {} {} from", what, executable_path.display()).as_str(),
     )?;
+    db.record(&step, &[], &reason)?;
     Ok(())
 }
 
-pub fn run_stage(compilers: &Compilers, stage: &Stage) -> anyhow::Result<()> {
-    println!("{} {}", message!("Running stage"), stage.name);
+/// Compiles, links and creates the executable, recording inputs in `db`
+fn build_outputs(
+    src_files: &[SourceFile],
+    build_dir: &Path,
+    toolchain: &Toolchain,
+    cache: Option<&ObjectCache>,
+    db: &mut BuildDb,
+    config: &Config,
+    stage: &Stage,
+) -> anyhow::Result<()> {
+    let project = &config.project;
+    let headers = [version_header(project), config_header(config, stage)?];
+    for (name, contents) in headers.into_iter().flatten() {
+        write_generated(
+            &generated_dir(build_dir, stage).join(name),
+            &contents,
+            db,
+            stage,
+        )?;
+    }
+    write_configure_files(config, build_dir, db, stage)?;
+    write_embeds(config, build_dir, db, stage)?;
 
-    let (src_dir, build_dir) = get_dirs(&stage)?;
+    let out_files = compile_src_files(src_files, toolchain, cache, db, stage)?;
+    if out_files.is_empty() {
+        bail!(error!("No object files were created"));
+    }
 
-    setup_build_dir(&src_dir, &build_dir, &stage)?;
+    let obj_files = match stage.build.link {
+        LinkMode::Direct => out_files,
+        LinkMode::Relocatable if out_files.len() > 1 => {
+            toolchain.tool(&toolchain.compilers.linker, "linker")?;
+            vec![link_object_files(
+                &out_files, build_dir, toolchain, db, stage,
+            )?]
+        }
+        LinkMode::Relocatable => out_files,
+    };
 
-    let src_files = get_src_files(&src_dir, &stage)?;
+    let cxx = src_files.iter().any(|file| file.lang == Language::Cxx);
+    if let Some(kind) = stage.build.library {
+        let library = library_path(kind, build_dir, toolchain, stage)?;
+        match kind {
+            LibraryKind::Static => {
+                create_static_library(&library, &obj_files, toolchain, db, stage)?
+            }
+            LibraryKind::Shared => {
+                create_executable(&library, &obj_files, cxx, true, toolchain, db, stage)?
+            }
+        }
+        write_pc_files(&library, kind, build_dir, db, project, stage)?;
+    } else if stage.build.build_executable {
+        let executable_path = executable_path(executable_name(project, stage), build_dir, stage)?;
+        create_executable(
+            &executable_path,
+            &obj_files,
+            cxx,
+            false,
+            toolchain,
+            db,
+            stage,
+        )?;
+    }
+    Ok(())
+}
 
-    let out_files = compile_src_files(&src_files, &compilers, &stage)?;
+pub fn create_static_library(
+    library: &Path,
+    obj_files: &[PathBuf],
+    toolchain: &Toolchain,
+    db: &mut BuildDb,
+    stage: &Stage,
+) -> anyhow::Result<()> {
+    let archiver = &toolchain.compilers.archiver;
+    let mut cmd = Command::new(archiver);
+    cmd.arg("rcs").arg(library).args(obj_files);
 
-    let obj_file = if out_files.len() > 1 {
-        link_object_files(&out_files, &build_dir, &compilers, &stage)?
-    } else {
-        if let Some(object) = out_files.first() {
-            object.to_owned()
-        } else {
-            bail!(error!("No object files were created"));
+    let command = fingerprint(cmd.get_program(), cmd.get_args());
+    let step = Step {
+        stage: &stage.name,
+        output: library,
+        inputs: obj_files,
+        command: &command,
+        exact: true,
+    };
+    let reason = match db.stale_reason(&step, stage.build.staleness)? {
+        Some(reason) => reason,
+        None => {
+            println!(
+                "{}: {} is up to date",
+                info!("Skipping library step"),
+                bold!("{}", library.file_name().unwrap().to_str().unwrap())
+            );
+            return Ok(());
         }
     };
 
-    if stage.build.build_executable {
-        let executable_name = match &stage.build.executable {
-            Some(name) => name,
-            None => "a.out",
+    println!("{} {}", message!("Creating library"), library.display());
+    // ar only adds and replaces members, so objects of deleted sources would
+    // stay in an existing archive
+    if library.exists() {
+        fs::remove_file(library)
+            .with_context(|| error!("Failed to remove {}", library.display()))?;
+    }
+    toolchain.tool(archiver, "archiver")?;
+    let output = cmd
+        .spawn()
+        .and_then(|child| child.wait_with_output())
+        .with_context(|| error!("Failed to spawn {} process", archiver))?;
+    process_output(
+        output,
+        archiver,
+        &library.display().to_string(),
+        "create library",
+    )?;
+    db.record(&step, &[], &reason)?;
+    Ok(())
+}
+
+/// Writes `lib<name>.pc` for installed use, with a `/usr/local` prefix, and
+/// `lib<name>-uninstalled.pc` pointing into the project, which pkg-config
+/// prefers when both are on `PKG_CONFIG_PATH`
+fn write_pc_files(
+    library: &Path,
+    kind: LibraryKind,
+    build_dir: &Path,
+    db: &mut BuildDb,
+    project: &Project,
+    stage: &Stage,
+) -> anyhow::Result<()> {
+    let current_dir = env::current_dir()?;
+    let library_dir = current_dir.join(library.parent().unwrap_or(build_dir));
+    let installed = pkgconfig::installed_pc_file(kind, stage, project, Path::new("/usr/local"));
+    let include_dirs: Vec<String> = stage
+        .includes
+        .include_dirs
+        .iter()
+        .map(|dir| current_dir.join(dir).display().to_string())
+        .collect();
+    let uninstalled = pkgconfig::pc_file(
+        &stage.name,
+        kind,
+        stage,
+        project,
+        &[("libdir", library_dir.display().to_string())],
+        &include_dirs,
+    );
+
+    for (path, contents) in pc_paths(build_dir, stage)
+        .iter()
+        .zip([installed, uninstalled])
+    {
+        write_generated(path, &contents, db, stage)?;
+    }
+    Ok(())
+}
+
+/// Fills in the `[[configure]]` templates for `stage`
+fn write_configure_files(
+    config: &Config,
+    build_dir: &Path,
+    db: &mut BuildDb,
+    stage: &Stage,
+) -> anyhow::Result<()> {
+    if config.configure_files.is_empty() {
+        return Ok(());
+    }
+    let variables = configure_variables(config, stage);
+    for file in &config.configure_files {
+        let inside_generated_dir = file
+            .output
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !inside_generated_dir {
+            bail!(error!(
+                "Configured file {} must be a relative path without ..",
+                file.output.display()
+            ));
+        }
+        let template = fs::read_to_string(&file.input)
+            .with_context(|| error!("Could not read {}", file.input.display()))?;
+        let (contents, unknown) = configure(&template, &variables);
+        if !unknown.is_empty() {
+            let unknown: Vec<String> = unknown.into_iter().collect();
+            println!(
+                "{}: undefined variables in {} were left empty: {}",
+                warning!("Warning"),
+                file.input.display(),
+                unknown.join(", ")
+            );
+        }
+        write_generated(
+            &generated_dir(build_dir, stage).join(&file.output),
+            &contents,
+            db,
+            stage,
+        )?;
+    }
+    Ok(())
+}
+
+/// The generated sources of the stage's `[[embed]]`s, compiled like its own
+fn embed_sources(config: &Config, stage: &Stage) -> anyhow::Result<Vec<SourceFile>> {
+    let current_dir = env::current_dir()?;
+    let generated_dir = generated_dir(&stage.build.build_dir, stage);
+    Ok(stage_embeds(config, stage)?
+        .into_iter()
+        .map(|(_, name)| {
+            let file_name = format!("{}.c", name);
+            SourceFile {
+                path: current_dir.join(&generated_dir).join(&file_name),
+                out_path: stage
+                    .build
+                    .build_dir
+                    .join("objects")
+                    .join("embed")
+                    .join(&stage.name)
+                    .join(&file_name),
+                name: file_name,
+                lang: Language::C,
+                compiler: None,
+                flags: Vec::new(),
+            }
+        })
+        .collect())
+}
+
+/// Writes the source and header of every `[[embed]]` of `stage` whose file
+/// changed
+fn write_embeds(
+    config: &Config,
+    build_dir: &Path,
+    db: &mut BuildDb,
+    stage: &Stage,
+) -> anyhow::Result<()> {
+    let embeds = stage_embeds(config, stage)?;
+    if embeds.is_empty() {
+        return Ok(());
+    }
+    let generated_dir = generated_dir(build_dir, stage);
+    let objects_dir = build_dir.join("objects").join("embed").join(&stage.name);
+    fs::create_dir_all(&objects_dir)
+        .with_context(|| error!("Failed to create {}", objects_dir.display()))?;
+    for (embed, name) in embeds {
+        let header = generated_dir.join(format!("{}.h", name));
+        write_generated(&header, &embed_header(&name, &embed.file), db, stage)?;
+
+        let source = generated_dir.join(format!("{}.c", name));
+        let command = fingerprint(OsStr::new("embed"), [OsStr::new(&name)].into_iter());
+        let step = Step {
+            stage: &stage.name,
+            output: &source,
+            inputs: std::slice::from_ref(&embed.file),
+            command: &command,
+            exact: true,
         };
-        create_executable(executable_name, &obj_file, &build_dir, &compilers, &stage)?;
+        let reason = match db.stale_reason(&step, stage.build.staleness)? {
+            Some(reason) => reason,
+            None => continue,
+        };
+        let contents = fs::read(&embed.file)
+            .with_context(|| error!("Could not read {}", embed.file.display()))?;
+        println!("{} {}", message!("Embedding"), embed.file.display());
+        fs::write(&source, embed_source(&name, &embed.file, &contents))
+            .with_context(|| error!("Failed to write {}", source.display()))?;
+        db.record(&step, &[], &reason)?;
+    }
+    Ok(())
+}
+
+/// Writes a file cbt generates, unless it already has `contents`, so its
+/// mtime only changes, and things including it only rebuild, when it does
+fn write_generated(
+    path: &Path,
+    contents: &str,
+    db: &mut BuildDb,
+    stage: &Stage,
+) -> anyhow::Result<()> {
+    let command = fingerprint(OsStr::new(contents), std::iter::empty());
+    let step = Step {
+        stage: &stage.name,
+        output: path,
+        inputs: &[],
+        command: &command,
+        exact: true,
+    };
+    let reason = match db.stale_reason(&step, stage.build.staleness)? {
+        Some(reason) => reason,
+        None => return Ok(()),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| error!("Failed to create {}", dir.display()))?;
+    }
+    println!("{} {}", message!("Writing"), path.display());
+    fs::write(path, contents).with_context(|| error!("Failed to write {}", path.display()))?;
+    db.record(&step, &[], &reason)
+}
+
+/// Deletes outputs of deleted or excluded sources, renamed executables and
+/// removed stages, which the build would otherwise leave behind forever
+fn remove_stale_outputs(config: &Config, db: &mut BuildDb, stage: &Stage) -> anyhow::Result<()> {
+    let stages: Vec<&str> = config
+        .stages
+        .iter()
+        .map(|stage| stage.name.as_str())
+        .collect();
+    for output in db.stale_outputs(&stage.name, &stages) {
+        for file in output_files(&output) {
+            if file.is_file() {
+                println!("{} {}", message!("Removing stale"), file.display());
+                fs::remove_file(&file)
+                    .with_context(|| error!("Failed to remove {}", file.display()))?;
+            }
+        }
+        db.forget(&output);
+    }
+    Ok(())
+}
+
+pub fn run_stage(
+    config: &Config,
+    toolchain: &Toolchain,
+    cache: Option<&ObjectCache>,
+    stage: &Stage,
+) -> anyhow::Result<()> {
+    println!("{} {}", message!("Running stage"), stage.name);
+
+    let (src_dir, build_dir) = get_dirs(stage)?;
+
+    setup_build_dir(&src_dir, &build_dir, stage)?;
+
+    let mut src_files = get_src_files(&src_dir, stage, &config.extensions)?;
+    src_files.extend(embed_sources(config, stage)?);
+    if !src_files.iter().any(|file| file.lang.is_compiled()) {
+        bail!(error!("No source files found in source directory"));
+    }
+    check_output_collisions(&src_files, &build_dir, toolchain, &config.project, stage)?;
+
+    // Fail before compiling anything if a needed tool is missing
+    for file in src_files.iter().filter(|file| file.lang.is_compiled()) {
+        if file.compiler.is_none() {
+            let role = file.lang.compiler_role(stage.assembler);
+            let program = match role {
+                "cxx" => toolchain.compilers.cxx.clone(),
+                "asm" => stage.assembler.program(&toolchain.compilers),
+                _ => toolchain.compilers.cc.clone(),
+            };
+            toolchain.tool(&program, role)?;
+        }
     }
 
+    let mut db = BuildDb::load(&build_dir)?;
+    let built = build_outputs(
+        &src_files, &build_dir, toolchain, cache, &mut db, config, stage,
+    );
+    if built.is_ok() {
+        remove_stale_outputs(config, &mut db, stage)?;
+        for dir in prune_dir_structure(&src_dir, &build_dir.join("objects"), stage)? {
+            println!("{} {}", message!("Removing stale directory"), dir.display());
+        }
+    }
+    // Keep what was recorded even if a later step failed
+    db.save()?;
+    built?;
+
     if let Some(post_script) = &stage.post_script {
-        let (_exit_code, output, error) =
-            run_script::run(post_script, &vec![], &ScriptOptions::new())
-                .with_context(|| error!("Failed to run post script for {}", stage.name))?;
-        if error.len() > 0 {
+        // Let scripts use the same tools as the build, e.g. `$OBJCOPY`
+        let compilers = &toolchain.compilers;
+        let mut options = ScriptOptions::new();
+        options.env_vars = Some(HashMap::from([
+            ("CC".to_owned(), compilers.cc.clone()),
+            ("CXX".to_owned(), compilers.cxx.clone()),
+            ("AS".to_owned(), stage.assembler.program(compilers)),
+            ("LD".to_owned(), compilers.linker.clone()),
+            ("AR".to_owned(), compilers.archiver.clone()),
+            ("OBJCOPY".to_owned(), compilers.objcopy.clone()),
+        ]));
+        let (_exit_code, output, error) = run_script::run(post_script, &vec![], &options)
+            .with_context(|| error!("Failed to run post script for {}", stage.name))?;
+        if !error.is_empty() {
             println!("{}", error);
         }
-        if output.len() > 0 {
+        if !output.is_empty() {
             println!("{}", output);
         }
     }
     message!("{} stage {}", message!("Finished"), stage.name);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outputs(paths: &[&str]) -> Vec<(PathBuf, String)> {
+        paths
+            .iter()
+            .map(|path| (PathBuf::from(path), path.trim_end_matches(".o").to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn outputs_differing_in_case_collide_only_when_folding() {
+        let outputs = outputs(&["build/Foo.c.o", "build/foo.c.o", "build/foo.C.o"]);
+        assert_eq!(find_collision(&outputs, false), None);
+        assert_eq!(
+            find_collision(&outputs, true),
+            Some(("build/Foo.c", "build/foo.c", Path::new("build/foo.c.o")))
+        );
+    }
+
+    #[test]
+    fn configured_assembler_only_applies_to_its_dialect() {
+        let mut compilers = Compilers::default();
+        assert_eq!(Assembler::Nasm.program(&compilers), "nasm");
+        assert_eq!(Assembler::Gas.program(&compilers), "as");
+
+        compilers.asm = "nasm".to_owned();
+        assert_eq!(Assembler::Nasm.program(&compilers), "nasm");
+        assert_eq!(Assembler::Yasm.program(&compilers), "yasm");
+        assert_eq!(Assembler::Gas.program(&compilers), "as");
+
+        compilers.asm = "/usr/bin/aarch64-linux-gnu-as".to_owned();
+        assert_eq!(Assembler::Gas.program(&compilers), compilers.asm);
+        assert_eq!(Assembler::Nasm.program(&compilers), "nasm");
+
+        // Unknown names are taken to be what the stage wants
+        compilers.asm = "/opt/tools/myasm".to_owned();
+        assert_eq!(Assembler::Yasm.program(&compilers), compilers.asm);
+        assert_eq!(Assembler::Cc.program(&compilers), compilers.cc);
+    }
+
+    #[test]
+    fn ldflags_go_through_the_driver() {
+        let ldflags = |flags: &[&str]| {
+            ldflag_args(
+                &flags
+                    .iter()
+                    .map(|flag| flag.to_string())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(
+            ldflags(&["--gc-sections", "-Wl,-z,now", "-Map=app.map"]).unwrap(),
+            ["-Wl,--gc-sections", "-Wl,-z,now", "-Wl,-Map=app.map"]
+        );
+        assert!(ldflags(&[]).unwrap().is_empty());
+        for relocatable in ["-r", "-relocatable", "--relocatable", "-Wl,-r"] {
+            assert!(ldflags(&["-s", relocatable]).is_err(), "{}", relocatable);
+        }
+    }
+
+    #[test]
+    fn identical_outputs_always_collide() {
+        let outputs = outputs(&["build/a.c.o", "build/b.c.o", "build/a.c.o"]);
+        assert_eq!(
+            find_collision(&outputs, false),
+            Some(("build/a.c", "build/a.c", Path::new("build/a.c.o")))
+        );
+    }
+}
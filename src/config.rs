@@ -1,16 +1,244 @@
-use std::{fs, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use serde_derive::{Deserialize, Serialize};
 
-use crate::error;
+use crate::{error, files::Language};
 
 #[derive(Deserialize, Serialize)]
 pub struct Config {
+    /// Toolchain file merged over `[compilers]`, overridden by `--toolchain`
+    pub toolchain: Option<PathBuf>,
+    #[serde(default)]
+    pub project: Project,
+    /// Writes `<name>_config.h` next to the version header when present
+    pub config_header: Option<ConfigHeader>,
+    #[serde(default)]
     pub compilers: Compilers,
     #[serde(rename(deserialize = "stage"))]
     #[serde(rename(serialize = "stages"))]
     pub stages: Vec<Stage>,
+    #[serde(default, rename = "check")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub checks: Vec<Check>,
+    #[serde(default, rename = "configure")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub configure_files: Vec<ConfigureFile>,
+    #[serde(default, rename = "embed")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub embeds: Vec<Embed>,
+    #[serde(default)]
+    pub extensions: Extensions,
+    #[serde(default, rename = "profile")]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(default, rename = "target")]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub targets: BTreeMap<String, Target>,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub dist: Dist,
+    /// The profile the build applied, set by `cbt build`
+    #[serde(skip)]
+    pub profile: Option<String>,
+    /// The directory of the config file, which paths in it are relative to
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+/// Metadata for the `.pc` files, the dist tarball and the generated version
+/// header
+#[derive(Deserialize, Serialize, Default)]
+pub struct Project {
+    /// Also the default executable name. The tarball falls back to the name
+    /// of the project directory.
+    pub name: Option<String>,
+    /// Defaults to "0.0.0"
+    pub version: Option<String>,
+    pub description: Option<String>,
+    pub license: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<String>,
+}
+
+impl Project {
+    pub fn version(&self) -> &str {
+        self.version.as_deref().unwrap_or("0.0.0")
+    }
+}
+
+/// `<name>_config.h`, which also has the git commit and the build profile
+#[derive(Deserialize, Serialize, Default)]
+pub struct ConfigHeader {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub defines: BTreeMap<String, Define>,
+}
+
+/// A feature check, run for every stage by compiling a probe program with
+/// its C compiler and flags
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Check {
+    #[serde(flatten)]
+    pub kind: CheckKind,
+    /// The macro to define, e.g. `HAVE_SYS_EPOLL_H` for a header check.
+    /// Required for `compiles`.
+    pub define: Option<String>,
+    /// Headers the probe includes, e.g. for a function declared in them
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub includes: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckKind {
+    /// Defines `HAVE_<HEADER>` if the header can be included
+    Header(String),
+    /// Defines `HAVE_<FUNCTION>` if a program calling it links
+    Function(String),
+    /// Defines `SIZEOF_<TYPE>` to the size of the type, if it exists
+    Sizeof(String),
+    /// Defines `define` if the snippet compiles as a file of its own
+    Compiles(String),
+}
+
+/// A file generated from a template with `@VAR@`, `${VAR}`, `#cmakedefine`
+/// and `#cmakedefine01`, like CMake's `configure_file`. Variables are the
+/// `PROJECT_*` metadata, the config header defines and the stage's defines,
+/// which include the check results.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ConfigureFile {
+    pub input: PathBuf,
+    /// Relative to each stage's generated directory, which is on its include
+    /// path
+    pub output: PathBuf,
+}
+
+/// A file compiled into the stage as `const unsigned char <name>[]` and
+/// `const size_t <name>_len`, declared in the generated `<name>.h`
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Embed {
+    pub file: PathBuf,
+    /// Defaults to the file name as an identifier, e.g. `index_html`
+    pub name: Option<String>,
+    /// Only embed into this stage instead of every stage
+    pub stage: Option<String>,
+}
+
+/// What `cbt dist` packs besides the config, the stages' sources and their
+/// include directories. The tarball's name and version come from
+/// `[project]`, so other keys are rejected.
+#[derive(Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Dist {
+    /// Extra files or directories, e.g. a README, licence or toolchain files
+    #[serde(default)]
+    pub files: Vec<PathBuf>,
+}
+
+/// The shared object cache, in `CBT_CACHE_DIR`, `dir`, or `~/.cache/cbt`
+#[derive(Deserialize, Serialize, Default)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    pub dir: Option<PathBuf>,
+    /// Least recently used objects are evicted past this, defaults to 5G
+    pub max_size: Option<Size>,
+    pub remote: Option<RemoteCacheConfig>,
+}
+
+/// `[cache.remote]`, an HTTP cache shared between machines
+#[derive(Deserialize, Serialize)]
+pub struct RemoteCacheConfig {
+    pub url: String,
+    /// Only fetch objects, never upload them. `CBT_REMOTE_CACHE_READ_ONLY`
+    /// overrides this, so CI can upload with the same config.
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+/// A `[target.<triple>]` section, selected with `--target`, or a standalone
+/// toolchain file. For targets, compilers that aren't given default to the
+/// GNU cross names, e.g. `aarch64-linux-gnu-gcc`.
+#[derive(Deserialize, Serialize, Default)]
+pub struct Target {
+    pub cc: Option<String>,
+    pub cxx: Option<String>,
+    pub asm: Option<String>,
+    pub linker: Option<String>,
+    pub archiver: Option<String>,
+    pub objcopy: Option<String>,
+    pub sysroot: Option<PathBuf>,
+    /// Passed as `-T` when creating executables
+    pub linker_script: Option<PathBuf>,
+    /// Placed before every stage's own flags
+    #[serde(default)]
+    pub flags: Flags,
+}
+
+/// Settings layered on top of every stage, selected with `--profile`
+#[derive(Deserialize, Serialize, Default)]
+pub struct Profile {
+    #[serde(flatten)]
+    pub settings: Settings,
+    #[serde(default)]
+    pub defines: BTreeMap<String, Define>,
+}
+
+/// Compiler-agnostic settings, translated into flags for the detected
+/// compiler family. They come before the stage's own flags, so those win.
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct Settings {
+    pub c_std: Option<String>,
+    pub cxx_std: Option<String>,
+    pub opt_level: Option<OptLevel>,
+    pub debug_info: Option<bool>,
+    pub warnings: Option<Warnings>,
+    pub warnings_as_errors: Option<bool>,
+}
+
+/// `opt_level = 2` or `opt_level = "s"`
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum OptLevel {
+    Level(u8),
+    Named(String),
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Warnings {
+    None,
+    Default,
+    All,
+    Extra,
+    Pedantic,
+}
+
+/// A preprocessor define. Strings become C string literals,
+/// `{ raw = "..." }` is used verbatim as the macro body, and `false` leaves
+/// the macro undefined.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Define {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Raw { raw: String },
 }
 
 #[derive(Deserialize, Serialize)]
@@ -18,28 +246,77 @@ pub struct Stage {
     #[serde(default)]
     pub name: String,
     #[serde(default)]
+    pub assembler: Assembler,
+    #[serde(flatten)]
+    pub settings: Settings,
+    #[serde(default)]
     pub flags: Flags,
     pub includes: Includes,
     #[serde(default)]
     pub exclude: Exclude,
+    #[serde(default)]
+    pub libraries: Libraries,
+    #[serde(default)]
+    pub install: Install,
     pub source: Source,
     pub build: Build,
     pub post_script: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub defines: BTreeMap<String, Define>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, Dependency>,
+    /// Results of the `[[check]]`s for this stage, set by `cbt build`. They
+    /// are in `defines` too.
+    #[serde(skip)]
+    pub check_defines: BTreeMap<String, Define>,
+    /// `defines` as written in the config, before profiles, `--define` and
+    /// checks add to them. Only these describe the library to its users.
+    #[serde(skip)]
+    pub config_defines: BTreeMap<String, Define>,
 }
 
-#[derive(Deserialize, Serialize)]
+/// A system library found at build time, e.g.
+/// `zlib = { pkg_config = "zlib >= 1.2" }`
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Dependency {
+    /// The pkg-config module, optionally with version constraints
+    pub pkg_config: String,
+}
+
+/// How `.s`/`.asm` files are assembled. `.S` files always go through `cc`.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Assembler {
+    #[default]
+    Nasm,
+    Yasm,
+    #[serde(alias = "as")]
+    Gas,
+    Cc,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Compilers {
-    #[serde(default)]
+    #[serde(default = "default_cc")]
     pub cc: String,
-    #[serde(default)]
+    #[serde(default = "default_cxx")]
     pub cxx: String,
     #[serde(default)]
     pub asm: String,
-    #[serde(default)]
+    #[serde(default = "default_linker")]
     pub linker: String,
+    #[serde(default = "default_archiver")]
+    pub archiver: String,
+    #[serde(default = "default_objcopy")]
+    pub objcopy: String,
+    /// Prefixed to every compile command, e.g. "ccache", "sccache" or "auto"
+    /// to use whichever is installed. `CBT_COMPILER_LAUNCHER` overrides it.
+    pub launcher: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Default)]
 pub struct Flags {
     #[serde(default)]
     pub cflags: Vec<String>,
@@ -59,12 +336,123 @@ pub struct Includes {
     pub include_prefix: String,
 }
 
+/// Maps file extensions (without the leading dot) to the language used to
+/// build them. Entries given in the config take precedence over the built-in
+/// defaults, which are always available as a fallback. An exact match wins,
+/// so `.C` and `.S` keep their meaning, then the lowercase extension is
+/// tried, so `.CPP` and `.Asm` still build.
 #[derive(Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Extensions {
+    pub map: BTreeMap<String, Extension>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Extension {
+    Language(Language),
+    Custom {
+        language: Language,
+        compiler: Option<String>,
+        #[serde(default)]
+        flags: Vec<String>,
+    },
+}
+
+#[derive(Deserialize, Serialize, Default)]
 pub struct Exclude {
     pub dirs: Vec<PathBuf>,
     pub files: Vec<PathBuf>,
 }
 
+/// Libraries the executable links against, after the objects
+#[derive(Deserialize, Serialize, Default)]
+pub struct Libraries {
+    /// In link order, e.g. `["foo", "m", "pthread"]`
+    #[serde(default)]
+    pub libs: Vec<Library>,
+    #[serde(default)]
+    pub lib_dirs: Vec<PathBuf>,
+    /// Run-time search paths, e.g. `"$ORIGIN/../lib"`
+    #[serde(default)]
+    pub rpath: Vec<String>,
+    /// Static libraries linked in full, even members nothing refers to, such
+    /// as self-registering plugins
+    #[serde(default)]
+    pub link_whole: Vec<Library>,
+    /// Link flags from `[stage.dependencies]`, after everything else
+    #[serde(skip)]
+    pub dependency_args: Vec<String>,
+}
+
+/// `"m"`, a path like `"vendor/libfoo.a"`, or
+/// `{ name = "foo", prefer = "static" }`
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Library {
+    Name(String),
+    Detailed {
+        name: String,
+        #[serde(default)]
+        prefer: LinkPreference,
+    },
+}
+
+/// Which of `libfoo.a` and `libfoo.so` the linker should pick when both exist
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkPreference {
+    #[default]
+    Default,
+    Static,
+    Dynamic,
+}
+
+impl Library {
+    pub fn name(&self) -> &str {
+        match self {
+            Library::Name(name) => name,
+            Library::Detailed { name, .. } => name,
+        }
+    }
+
+    pub fn prefer(&self) -> LinkPreference {
+        match self {
+            Library::Name(_) => LinkPreference::Default,
+            Library::Detailed { prefer, .. } => *prefer,
+        }
+    }
+
+    /// A file to link as-is rather than a name to search for with `-l`
+    pub fn path(&self) -> Option<&Path> {
+        let name = self.name();
+        let is_path = name.contains('/')
+            || [".a", ".so", ".lib", ".dylib"]
+                .iter()
+                .any(|ext| name.ends_with(ext))
+            || name.contains(".so.");
+        if is_path {
+            Some(Path::new(name))
+        } else {
+            None
+        }
+    }
+}
+
+/// What `cbt install` copies for a stage
+#[derive(Deserialize, Serialize)]
+pub struct Install {
+    /// The executable goes to `bin/`, a library to `lib/` with its `.pc`
+    /// file in `lib/pkgconfig/`
+    #[serde(default = "default_true")]
+    pub artifact: bool,
+    /// Directories whose contents are copied to `include/`
+    #[serde(default)]
+    pub headers: Vec<PathBuf>,
+    /// Subdirectory of `include/` to put the headers in
+    pub include_subdir: Option<PathBuf>,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Source {
     pub source_dir: PathBuf,
@@ -77,6 +465,45 @@ pub struct Build {
     pub executable: Option<String>,
     pub executable_extra_flags: Option<Vec<String>>,
     pub build_executable: bool,
+    #[serde(default)]
+    pub staleness: Staleness,
+    #[serde(default)]
+    pub link: LinkMode,
+    /// Build `lib<stage name>.a` or `.so` instead of an executable
+    pub library: Option<LibraryKind>,
+    /// Version written to the library's `.pc` file
+    pub version: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LibraryKind {
+    Static,
+    Shared,
+}
+
+/// How objects become the executable. `direct` hands every object to the
+/// compiler driver (`cxx` if the stage has C++ sources, else `cc`), passing
+/// `ldflags` on to the linker with `-Wl,`. `relocatable` first combines them
+/// with `ld -r` and `ldflags` into one object, which is also useful with
+/// `build_executable = false`.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    #[default]
+    Direct,
+    Relocatable,
+}
+
+/// How outputs are judged out of date. `content` compares hashes of the
+/// inputs recorded in the build database, so touching a file or checking
+/// out a branch and back doesn't rebuild anything.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Staleness {
+    #[default]
+    Timestamp,
+    Content,
 }
 
 impl Default for Config {
@@ -88,10 +515,31 @@ impl Default for Config {
                 source: Default::default(),
                 build: Default::default(),
                 exclude: Default::default(),
+                libraries: Default::default(),
+                install: Default::default(),
                 flags: Default::default(),
                 includes: Default::default(),
+                assembler: Default::default(),
+                settings: Default::default(),
                 post_script: None,
+                defines: Default::default(),
+                dependencies: Default::default(),
+                check_defines: Default::default(),
+                config_defines: Default::default(),
             }],
+            toolchain: None,
+            checks: Default::default(),
+            configure_files: Default::default(),
+            embeds: Default::default(),
+            project: Default::default(),
+            config_header: None,
+            extensions: Default::default(),
+            profiles: Default::default(),
+            targets: Default::default(),
+            cache: Default::default(),
+            dist: Default::default(),
+            profile: None,
+            dir: PathBuf::new(),
         }
     }
 }
@@ -103,9 +551,17 @@ impl Default for Stage {
             flags: Default::default(),
             includes: Default::default(),
             exclude: Default::default(),
+            libraries: Default::default(),
+            install: Default::default(),
             source: Default::default(),
             build: Default::default(),
+            assembler: Default::default(),
+            settings: Default::default(),
             post_script: None,
+            defines: Default::default(),
+            dependencies: Default::default(),
+            check_defines: Default::default(),
+            config_defines: Default::default(),
         }
     }
 }
@@ -113,23 +569,39 @@ impl Default for Stage {
 impl Default for Compilers {
     fn default() -> Self {
         Self {
-            cc: "gcc".to_owned(),
-            cxx: "g++".to_owned(),
-            asm: "nasm".to_owned(),
-            linker: "ld".to_owned(),
+            cc: default_cc(),
+            cxx: default_cxx(),
+            asm: String::new(),
+            linker: default_linker(),
+            archiver: default_archiver(),
+            objcopy: default_objcopy(),
+            launcher: None,
         }
     }
 }
 
-impl Default for Flags {
-    fn default() -> Self {
-        Self {
-            cflags: vec![],
-            cxxflags: vec![],
-            asmflags: vec!["-felf64".to_owned()],
-            ldflags: vec![],
-        }
-    }
+fn default_true() -> bool {
+    true
+}
+
+fn default_cc() -> String {
+    env::var("CC").unwrap_or_else(|_| "gcc".to_owned())
+}
+
+fn default_cxx() -> String {
+    env::var("CXX").unwrap_or_else(|_| "g++".to_owned())
+}
+
+fn default_linker() -> String {
+    env::var("LD").unwrap_or_else(|_| "ld".to_owned())
+}
+
+fn default_archiver() -> String {
+    env::var("AR").unwrap_or_else(|_| "ar".to_owned())
+}
+
+fn default_objcopy() -> String {
+    env::var("OBJCOPY").unwrap_or_else(|_| "objcopy".to_owned())
 }
 
 impl Default for Includes {
@@ -141,11 +613,111 @@ impl Default for Includes {
     }
 }
 
-impl Default for Exclude {
+const DEFAULT_EXTENSIONS: &[(&str, Language)] = &[
+    ("c", Language::C),
+    ("cc", Language::Cxx),
+    ("cpp", Language::Cxx),
+    ("cxx", Language::Cxx),
+    ("c++", Language::Cxx),
+    ("C", Language::Cxx),
+    ("s", Language::Asm),
+    ("asm", Language::Asm),
+    ("S", Language::AsmCpp),
+    ("h", Language::Header),
+    ("hh", Language::Header),
+    ("hpp", Language::Header),
+    ("hxx", Language::Header),
+    ("h++", Language::Header),
+    ("inc", Language::Header),
+];
+
+impl Default for Extensions {
     fn default() -> Self {
         Self {
-            dirs: vec![],
-            files: vec![],
+            map: DEFAULT_EXTENSIONS
+                .iter()
+                .map(|(ext, lang)| (ext.to_string(), Extension::Language(*lang)))
+                .collect(),
+        }
+    }
+}
+
+impl Extensions {
+    pub fn get(&self, ext: &str) -> Option<Extension> {
+        self.get_exact(ext)
+            .or_else(|| self.get_exact(&ext.to_ascii_lowercase()))
+    }
+
+    fn get_exact(&self, ext: &str) -> Option<Extension> {
+        if let Some(extension) = self.map.get(ext) {
+            return Some(extension.clone());
+        }
+        DEFAULT_EXTENSIONS
+            .iter()
+            .find(|(default, _)| *default == ext)
+            .map(|(_, lang)| Extension::Language(*lang))
+    }
+}
+
+impl Extension {
+    pub fn language(&self) -> Language {
+        match self {
+            Extension::Language(lang) => *lang,
+            Extension::Custom { language, .. } => *language,
+        }
+    }
+}
+
+impl Settings {
+    /// Overrides every setting that `other` sets
+    pub fn merge(&mut self, other: &Settings) {
+        if other.c_std.is_some() {
+            self.c_std = other.c_std.clone();
+        }
+        if other.cxx_std.is_some() {
+            self.cxx_std = other.cxx_std.clone();
+        }
+        if other.opt_level.is_some() {
+            self.opt_level = other.opt_level.clone();
+        }
+        if other.debug_info.is_some() {
+            self.debug_info = other.debug_info;
+        }
+        if other.warnings.is_some() {
+            self.warnings = other.warnings;
+        }
+        if other.warnings_as_errors.is_some() {
+            self.warnings_as_errors = other.warnings_as_errors;
+        }
+    }
+}
+
+impl Define {
+    /// The macro body, or `None` if the macro should not be defined at all
+    pub fn value(&self) -> Option<String> {
+        match self {
+            Define::Bool(true) => Some("1".to_owned()),
+            Define::Bool(false) => None,
+            Define::Int(value) => Some(value.to_string()),
+            Define::Str(value) => Some(format!(
+                "\"{}\"",
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )),
+            Define::Raw { raw } => Some(raw.clone()),
+        }
+    }
+
+    /// Parses a `NAME=value` or bare `NAME` argument from `--define`. Like
+    /// `-D`, the value is used verbatim.
+    pub fn parse_arg(arg: &str) -> (String, Define) {
+        match arg.split_once('=') {
+            Some((name, value)) => (
+                name.to_owned(),
+                Define::Raw {
+                    raw: value.to_owned(),
+                },
+            ),
+            None => (arg.to_owned(), Define::Bool(true)),
         }
     }
 }
@@ -158,6 +730,16 @@ impl Default for Source {
     }
 }
 
+impl Default for Install {
+    fn default() -> Self {
+        Self {
+            artifact: true,
+            headers: Vec::new(),
+            include_subdir: None,
+        }
+    }
+}
+
 impl Default for Build {
     fn default() -> Self {
         Self {
@@ -166,14 +748,203 @@ impl Default for Build {
             executable: Some("default".to_owned()),
             executable_extra_flags: None,
             build_executable: true,
+            staleness: Default::default(),
+            link: Default::default(),
+            library: None,
+            version: None,
         }
     }
 }
 
 pub fn load_config(config_path: &PathBuf) -> anyhow::Result<Config> {
-    let config = fs::read_to_string(&config_path)
+    let config = fs::read_to_string(config_path)
         .with_context(|| error!("Failed to read config file {}", &config_path.display()))?;
-    let config: Config = toml::from_str(&config)
+    let mut config: Config = toml::from_str(&config)
         .with_context(|| error!("Failed to parse config toml file from string"))?;
+    for stage in &mut config.stages {
+        stage.config_defines = stage.defines.clone();
+    }
+    check_stage_names(&config.stages)?;
     Ok(config)
 }
+
+/// The build database, check results and config headers of stages sharing a
+/// build directory are told apart by stage name, so those must be distinct
+fn check_stage_names(stages: &[Stage]) -> anyhow::Result<()> {
+    for (i, stage) in stages.iter().enumerate() {
+        for other in &stages[..i] {
+            if other.build.build_dir != stage.build.build_dir {
+                continue;
+            }
+            if stage.name.is_empty() || other.name.is_empty() {
+                bail!(error!(
+                    "Stages sharing build directory {} must each have a name",
+                    stage.build.build_dir.display()
+                ));
+            }
+            if stage.name == other.name {
+                bail!(error!(
+                    "Stages sharing build directory {} are both named {}",
+                    stage.build.build_dir.display(),
+                    stage.name
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Loads a toolchain file. Its sysroot and linker script are relative to the
+/// file itself.
+pub fn load_toolchain_file(path: &Path) -> anyhow::Result<Target> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| error!("Failed to read toolchain file {}", path.display()))?;
+    let mut toolchain: Target = toml::from_str(&contents)
+        .with_context(|| error!("Failed to parse toolchain file {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    toolchain.sysroot = toolchain.sysroot.map(|sysroot| dir.join(sysroot));
+    toolchain.linker_script = toolchain.linker_script.map(|script| dir.join(script));
+    Ok(toolchain)
+}
+
+/// A stage with just the required tables, building `src` into `build`, for
+/// tests to adjust
+#[cfg(test)]
+pub fn test_stage(name: &str) -> Stage {
+    let mut stage: Stage = toml::from_str(
+        r#"
+        includes = { include_dirs = [] }
+        source = { source_dir = "src" }
+        build = { build_dir = "build", build_executable = true }
+        "#,
+    )
+    .unwrap();
+    stage.name = name.to_owned();
+    stage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn language(extensions: &Extensions, ext: &str) -> Option<Language> {
+        extensions.get(ext).map(|extension| extension.language())
+    }
+
+    #[test]
+    fn extensions_match_exactly_before_lowercasing() {
+        let extensions = Extensions::default();
+        assert_eq!(language(&extensions, "c"), Some(Language::C));
+        assert_eq!(language(&extensions, "C"), Some(Language::Cxx));
+        assert_eq!(language(&extensions, "s"), Some(Language::Asm));
+        assert_eq!(language(&extensions, "S"), Some(Language::AsmCpp));
+        assert_eq!(language(&extensions, "CPP"), Some(Language::Cxx));
+        assert_eq!(language(&extensions, "Cpp"), Some(Language::Cxx));
+        assert_eq!(language(&extensions, "ASM"), Some(Language::Asm));
+        assert_eq!(language(&extensions, "H"), Some(Language::Header));
+        assert_eq!(language(&extensions, "txt"), None);
+    }
+
+    #[test]
+    fn configured_extensions_override_defaults() {
+        let extensions: Extensions = toml::from_str(
+            r#"
+            c = "cxx"
+            ino = { language = "cxx", compiler = "avr-g++", flags = ["-x", "c++"] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(language(&extensions, "c"), Some(Language::Cxx));
+        assert_eq!(language(&extensions, "INO"), Some(Language::Cxx));
+        assert_eq!(language(&extensions, "cpp"), Some(Language::Cxx));
+        match extensions.get("ino") {
+            Some(Extension::Custom {
+                compiler, flags, ..
+            }) => {
+                assert_eq!(compiler.as_deref(), Some("avr-g++"));
+                assert_eq!(flags, ["-x", "c++"]);
+            }
+            _ => panic!("ino should be a custom extension"),
+        }
+    }
+
+    #[test]
+    fn toolchain_file_paths_are_relative_to_it() {
+        let dir = std::env::temp_dir().join(format!("cbt-toolchain-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("arm.toml");
+        fs::write(
+            &path,
+            r#"
+            cc = "arm-none-eabi-gcc"
+            sysroot = "sysroot"
+            linker_script = "ld/app.ld"
+            flags = { cflags = ["-mthumb"] }
+            "#,
+        )
+        .unwrap();
+        let toolchain = load_toolchain_file(&path).unwrap();
+        assert_eq!(toolchain.cc.as_deref(), Some("arm-none-eabi-gcc"));
+        assert_eq!(toolchain.cxx, None);
+        assert_eq!(toolchain.sysroot, Some(dir.join("sysroot")));
+        assert_eq!(toolchain.linker_script, Some(dir.join("ld").join("app.ld")));
+        assert_eq!(toolchain.flags.cflags, ["-mthumb"]);
+        assert!(load_toolchain_file(&dir.join("missing.toml")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stages_sharing_a_build_dir_need_distinct_names() {
+        let stages = |names: [&str; 3]| -> Vec<Stage> {
+            names
+                .iter()
+                .zip(["build", "build", "other"])
+                .map(|(name, build_dir)| {
+                    let mut stage = Stage {
+                        name: name.to_string(),
+                        ..Default::default()
+                    };
+                    stage.build.build_dir = PathBuf::from(build_dir);
+                    stage
+                })
+                .collect()
+        };
+        assert!(check_stage_names(&stages(["app", "lib", "app"])).is_ok());
+        assert!(check_stage_names(&stages(["app", "", ""])).is_err());
+        assert!(check_stage_names(&stages(["lib", "lib", ""])).is_err());
+    }
+
+    #[test]
+    fn define_values() {
+        let defines: BTreeMap<String, Define> = toml::from_str(
+            r#"
+            ON = true
+            OFF = false
+            LEVEL = 3
+            NAME = 'say "hi" \ bye'
+            EXPR = { raw = "(1 << 4)" }
+            "#,
+        )
+        .unwrap();
+        let values: BTreeMap<_, _> = defines
+            .iter()
+            .map(|(name, define)| (name.as_str(), define.value()))
+            .collect();
+        assert_eq!(values["ON"].as_deref(), Some("1"));
+        assert_eq!(values["OFF"], None);
+        assert_eq!(values["LEVEL"].as_deref(), Some("3"));
+        assert_eq!(values["NAME"].as_deref(), Some(r#""say \"hi\" \\ bye""#));
+        assert_eq!(values["EXPR"].as_deref(), Some("(1 << 4)"));
+    }
+
+    #[test]
+    fn define_args_are_verbatim() {
+        let (name, define) = Define::parse_arg("PATH=\"/usr\"");
+        assert_eq!(name, "PATH");
+        assert_eq!(define.value().as_deref(), Some("\"/usr\""));
+        assert_eq!(
+            Define::parse_arg("FLAG"),
+            ("FLAG".to_owned(), Define::Bool(true))
+        );
+    }
+}
@@ -0,0 +1,491 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{cache::hex, config::Staleness, error};
+
+/// Bumped whenever the layout of the database changes. Databases with a
+/// different version are discarded, which just means a full rebuild.
+const SCHEMA_VERSION: u32 = 2;
+
+/// Everything cbt knows about the outputs in a build directory, kept in
+/// `<build_dir>/.cbt/db`
+#[derive(Deserialize, Serialize)]
+pub struct BuildDb {
+    version: u32,
+    #[serde(default)]
+    pub outputs: BTreeMap<PathBuf, OutputRecord>,
+    #[serde(skip)]
+    path: PathBuf,
+    /// Hashes computed during this build, so shared headers are read once
+    #[serde(skip)]
+    hashed: HashMap<PathBuf, FileHash>,
+    /// Outputs built or found up to date during this build
+    #[serde(skip)]
+    produced: HashSet<PathBuf>,
+}
+
+/// How an output was last built
+#[derive(Deserialize, Serialize, Default)]
+pub struct OutputRecord {
+    pub stage: String,
+    /// Hash of the program and arguments that built the output
+    pub command: String,
+    /// Hash of the output itself
+    pub hash: String,
+    /// Seconds since the Unix epoch
+    pub built_at: u64,
+    /// Why the output was last rebuilt
+    pub reason: String,
+    /// Sources or objects the output was built from
+    pub inputs: BTreeMap<PathBuf, FileHash>,
+    /// Headers the compiler reported reading
+    #[serde(default)]
+    pub headers: BTreeMap<PathBuf, FileHash>,
+}
+
+/// The content hash of a file, along with the modification time and size it
+/// had when hashed. If neither changed, the file isn't read again.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct FileHash {
+    pub hash: String,
+    pub mtime: u64,
+    pub size: u64,
+}
+
+/// What a step is about to build, to check against and then record in the
+/// database
+pub struct Step<'a> {
+    pub stage: &'a str,
+    pub output: &'a Path,
+    pub inputs: &'a [PathBuf],
+    /// See [`fingerprint`]
+    pub command: &'a str,
+    /// Whether the inputs must be exactly the recorded ones, e.g. for links.
+    /// Compiles also depend on headers that aren't known up front.
+    pub exact: bool,
+}
+
+impl BuildDb {
+    pub fn path(build_dir: &Path) -> PathBuf {
+        build_dir.join(".cbt").join("db")
+    }
+
+    pub fn load(build_dir: &Path) -> anyhow::Result<Self> {
+        let path = Self::path(build_dir);
+        let db = fs::read_to_string(&path)
+            .ok()
+            .and_then(|db| toml::from_str::<BuildDb>(&db).ok())
+            .filter(|db| db.version == SCHEMA_VERSION);
+        Ok(match db {
+            Some(db) => Self { path, ..db },
+            None => Self {
+                version: SCHEMA_VERSION,
+                outputs: BTreeMap::new(),
+                path,
+                hashed: HashMap::new(),
+                produced: HashSet::new(),
+            },
+        })
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| error!("Failed to create {}", dir.display()))?;
+        }
+        let db = toml::to_string(self).with_context(|| error!("Failed to serialize build db"))?;
+        fs::write(&self.path, db)
+            .with_context(|| error!("Failed to write {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Why `step` has to run, or `None` if its output is up to date.
+    /// `staleness` decides whether inputs are compared by modification time
+    /// or by content.
+    pub fn stale_reason(
+        &mut self,
+        step: &Step,
+        staleness: Staleness,
+    ) -> anyhow::Result<Option<String>> {
+        let record = match self.outputs.get(step.output) {
+            Some(record) => record,
+            None => return Ok(Some("no previous build recorded".to_owned())),
+        };
+        if record.command != step.command {
+            return Ok(Some("command changed".to_owned()));
+        }
+        if let Some(input) = step
+            .inputs
+            .iter()
+            .find(|input| !record.inputs.contains_key(*input))
+        {
+            return Ok(Some(format!("{} was added", input.display())));
+        }
+        if step.exact {
+            if let Some(input) = record
+                .inputs
+                .keys()
+                .find(|input| !step.inputs.contains(input))
+            {
+                return Ok(Some(format!("{} was removed", input.display())));
+            }
+        }
+        let reason = self.changed_input(step.output, staleness)?;
+        if reason.is_none() {
+            self.produced.insert(step.output.to_path_buf());
+            if staleness == Staleness::Content {
+                // Remember new mtimes so touched files aren't hashed again
+                self.refresh(step.output)?;
+            }
+        }
+        Ok(reason)
+    }
+
+    /// Why the recorded inputs of `output` make it stale, ignoring the
+    /// command, which only the build knows
+    pub fn changed_input(
+        &mut self,
+        output: &Path,
+        staleness: Staleness,
+    ) -> anyhow::Result<Option<String>> {
+        let output_mtime = match fs::metadata(output) {
+            Ok(metadata) => mtime(&metadata),
+            Err(_) => return Ok(Some("output is missing".to_owned())),
+        };
+        let recorded: Vec<(PathBuf, FileHash)> = match self.outputs.get(output) {
+            Some(record) => record
+                .inputs
+                .iter()
+                .chain(&record.headers)
+                .map(|(path, hash)| (path.clone(), hash.clone()))
+                .collect(),
+            None => return Ok(Some("no previous build recorded".to_owned())),
+        };
+        for (input, hash) in recorded {
+            let current = match staleness {
+                Staleness::Timestamp => match fs::metadata(&input) {
+                    Ok(metadata) if mtime(&metadata) > output_mtime => {
+                        return Ok(Some(format!("{} is newer", input.display())))
+                    }
+                    Ok(_) => continue,
+                    Err(_) => None,
+                },
+                Staleness::Content => self.hash(&input)?,
+            };
+            match current {
+                None => return Ok(Some(format!("{} is missing", input.display()))),
+                Some(current) if current.hash != hash.hash => {
+                    return Ok(Some(format!("{} changed", input.display())))
+                }
+                Some(_) => (),
+            }
+        }
+        Ok(None)
+    }
+
+    fn refresh(&mut self, output: &Path) -> anyhow::Result<()> {
+        let paths: Vec<PathBuf> = match self.outputs.get(output) {
+            Some(record) => record
+                .inputs
+                .keys()
+                .chain(record.headers.keys())
+                .cloned()
+                .collect(),
+            None => return Ok(()),
+        };
+        for path in paths {
+            if let Some(current) = self.hash(&path)? {
+                if let Some(record) = self.outputs.get_mut(output) {
+                    for hashes in [&mut record.inputs, &mut record.headers] {
+                        if let Some(hash) = hashes.get_mut(&path) {
+                            *hash = current.clone();
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records that `step` just ran because of `reason`, having read
+    /// `headers` besides its inputs
+    pub fn record(&mut self, step: &Step, headers: &[PathBuf], reason: &str) -> anyhow::Result<()> {
+        // The output and its inputs may have been rewritten by this step
+        self.hashed.remove(step.output);
+        let mut record = OutputRecord {
+            stage: step.stage.to_owned(),
+            command: step.command.to_owned(),
+            hash: match self.hash(step.output)? {
+                Some(hash) => hash.hash,
+                None => String::new(),
+            },
+            built_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0),
+            reason: reason.to_owned(),
+            ..Default::default()
+        };
+        for input in step.inputs {
+            if let Some(hash) = self.hash(input)? {
+                record.inputs.insert(input.clone(), hash);
+            }
+        }
+        for header in headers {
+            if record.inputs.contains_key(header) {
+                continue;
+            }
+            if let Some(hash) = self.hash(header)? {
+                record.headers.insert(header.clone(), hash);
+            }
+        }
+        self.outputs.insert(step.output.to_path_buf(), record);
+        self.produced.insert(step.output.to_path_buf());
+        Ok(())
+    }
+
+    /// Outputs of `stage` that this build didn't produce, and outputs of
+    /// stages that no longer exist
+    pub fn stale_outputs(&self, stage: &str, stages: &[&str]) -> Vec<PathBuf> {
+        self.outputs
+            .iter()
+            .filter(|(output, record)| {
+                (record.stage == stage && !self.produced.contains(*output))
+                    || !stages.contains(&record.stage.as_str())
+            })
+            .map(|(output, _)| output.clone())
+            .collect()
+    }
+
+    pub fn forget(&mut self, output: &Path) {
+        self.outputs.remove(output);
+    }
+
+    /// Hashes `path`, reusing the recorded hash if its mtime and size match.
+    /// Returns `None` if the file doesn't exist.
+    fn hash(&mut self, path: &Path) -> anyhow::Result<Option<FileHash>> {
+        if let Some(hash) = self.hashed.get(path) {
+            return Ok(Some(hash.clone()));
+        }
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(None),
+        };
+        let mtime = mtime(&metadata);
+        let size = metadata.len();
+
+        let known = self
+            .outputs
+            .values()
+            .filter_map(|record| record.inputs.get(path).or_else(|| record.headers.get(path)))
+            .find(|hash| hash.mtime == mtime && hash.size == size)
+            .cloned();
+        let hash = match known {
+            Some(hash) => hash,
+            None => {
+                let contents =
+                    fs::read(path).with_context(|| error!("Could not read {}", path.display()))?;
+                FileHash {
+                    hash: hex(&Sha256::digest(&contents)),
+                    mtime,
+                    size,
+                }
+            }
+        };
+        self.hashed.insert(path.to_path_buf(), hash.clone());
+        Ok(Some(hash))
+    }
+}
+
+/// `output` and, for object files, the dependency file written next to it.
+/// Other outputs keep their siblings, e.g. an executable `tool` and `tool.d`.
+pub fn output_files(output: &Path) -> Vec<PathBuf> {
+    let mut files = vec![output.to_path_buf()];
+    if output.extension().is_some_and(|ext| ext == "o") {
+        files.push(output.with_extension("d"));
+    }
+    files
+}
+
+/// Identifies a command line, so changed flags rebuild what they affect
+pub fn fingerprint<'a>(program: &OsStr, args: impl Iterator<Item = &'a OsStr>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(program.to_string_lossy().as_bytes());
+    for arg in args {
+        hasher.update([0]);
+        hasher.update(arg.to_string_lossy().as_bytes());
+    }
+    hex(&hasher.finalize())
+}
+
+fn mtime(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| mtime.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cbt-db-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Moves the mtime of `path` forward without changing its contents
+    fn touch(path: &Path) {
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+    }
+
+    /// The database as the next build sees it
+    fn reload(db: BuildDb, build_dir: &Path) -> BuildDb {
+        db.save().unwrap();
+        BuildDb::load(build_dir).unwrap()
+    }
+
+    #[test]
+    fn stale_only_when_content_or_command_changes() {
+        let dir = temp_dir("stale");
+        let source = dir.join("main.c");
+        let header = dir.join("main.h");
+        let output = dir.join("main.c.o");
+        fs::write(&source, "int main;").unwrap();
+        fs::write(&header, "#pragma once").unwrap();
+        fs::write(&output, "object").unwrap();
+        let inputs = [source.clone()];
+        let headers = [header.clone()];
+        let step = |command| Step {
+            stage: "app",
+            output: &output,
+            inputs: &inputs,
+            command,
+            exact: false,
+        };
+
+        let mut db = BuildDb::load(&dir).unwrap();
+        assert_eq!(
+            db.stale_reason(&step("cc"), Staleness::Content).unwrap(),
+            Some("no previous build recorded".to_owned())
+        );
+        db.record(&step("cc"), &headers, "first build").unwrap();
+        let mut db = reload(db, &dir);
+        assert_eq!(
+            db.stale_reason(&step("cc"), Staleness::Content).unwrap(),
+            None
+        );
+
+        // Touched but unchanged only matters when comparing timestamps
+        touch(&source);
+        let mut db = reload(db, &dir);
+        assert_eq!(
+            db.stale_reason(&step("cc"), Staleness::Content).unwrap(),
+            None
+        );
+        assert_eq!(
+            db.changed_input(&output, Staleness::Timestamp).unwrap(),
+            Some(format!("{} is newer", source.display()))
+        );
+
+        assert_eq!(
+            db.stale_reason(&step("cc -O2"), Staleness::Content)
+                .unwrap(),
+            Some("command changed".to_owned())
+        );
+
+        fs::write(&header, "#pragma once\nint x;").unwrap();
+        let mut db = reload(db, &dir);
+        assert_eq!(
+            db.stale_reason(&step("cc"), Staleness::Content).unwrap(),
+            Some(format!("{} changed", header.display()))
+        );
+        db.record(&step("cc"), &headers, "header changed").unwrap();
+
+        fs::write(&source, "int main2;").unwrap();
+        let mut db = reload(db, &dir);
+        assert_eq!(
+            db.changed_input(&output, Staleness::Content).unwrap(),
+            Some(format!("{} changed", source.display()))
+        );
+
+        fs::remove_file(&output).unwrap();
+        assert_eq!(
+            db.changed_input(&output, Staleness::Content).unwrap(),
+            Some("output is missing".to_owned())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_objects_have_dependency_files() {
+        assert_eq!(
+            output_files(Path::new("build/objects/main.c.o")),
+            [
+                PathBuf::from("build/objects/main.c.o"),
+                PathBuf::from("build/objects/main.c.d")
+            ]
+        );
+        assert_eq!(
+            output_files(Path::new("build/tool")),
+            [PathBuf::from("build/tool")]
+        );
+        assert_eq!(
+            output_files(Path::new("build/libfoo.so")),
+            [PathBuf::from("build/libfoo.so")]
+        );
+    }
+
+    #[test]
+    fn stale_outputs_of_a_stage() {
+        let dir = temp_dir("stale-outputs");
+        let source = dir.join("main.c");
+        fs::write(&source, "int main;").unwrap();
+        let inputs = [source.clone()];
+        let record = |db: &mut BuildDb, stage, output: &str| {
+            let output = dir.join(output);
+            fs::write(&output, "object").unwrap();
+            let step = Step {
+                stage,
+                output: &output,
+                inputs: &inputs,
+                command: "cc",
+                exact: false,
+            };
+            db.record(&step, &[], "test").unwrap();
+        };
+        let mut db = BuildDb::load(&dir).unwrap();
+        record(&mut db, "app", "main.c.o");
+        record(&mut db, "app", "removed.c.o");
+        record(&mut db, "lib", "lib.c.o");
+
+        // The next build of app only produces main.c.o
+        let mut db = reload(db, &dir);
+        record(&mut db, "app", "main.c.o");
+        assert_eq!(
+            db.stale_outputs("app", &["app", "lib"]),
+            [dir.join("removed.c.o")]
+        );
+        assert_eq!(
+            db.stale_outputs("app", &["app"]),
+            [dir.join("lib.c.o"), dir.join("removed.c.o")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::BTreeSet,
+    env, fs,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+use anyhow::{bail, Context};
+use flate2::{read::GzDecoder, Compression, GzBuilder};
+
+use crate::{
+    config::Config,
+    error,
+    files::{files_in, get_dirs, get_src_files},
+    message,
+};
+
+/// `<name>-<version>`, the tarball's name and its top-level directory
+pub fn dist_name(config: &Config) -> anyhow::Result<String> {
+    let name = match &config.project.name {
+        Some(name) => name.clone(),
+        None => env::current_dir()?
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or("project".to_owned()),
+    };
+    Ok(format!("{}-{}", name, config.project.version()))
+}
+
+/// Every file the tarball contains, relative to the project directory
+fn dist_files(config: &Config, config_file: &Path) -> anyhow::Result<BTreeSet<PathBuf>> {
+    let mut files = vec![config_file.to_path_buf()];
+    if let Some(toolchain) = &config.toolchain {
+        files.push(toolchain.clone());
+    }
+    files.extend(config.configure_files.iter().map(|file| file.input.clone()));
+    files.extend(config.embeds.iter().map(|embed| embed.file.clone()));
+    let current_dir = env::current_dir()?;
+    let project_dir = current_dir.canonicalize()?;
+    let mut build_dirs = Vec::new();
+    for stage in &config.stages {
+        let (src_dir, build_dir) = get_dirs(stage)?;
+        files.extend(
+            get_src_files(&src_dir, stage, &config.extensions)?
+                .into_iter()
+                .map(|file| file.path),
+        );
+        for dir in stage
+            .includes
+            .include_dirs
+            .iter()
+            .chain(&stage.install.headers)
+        {
+            // System and vendor include dirs, e.g. /opt/vendor/include, are
+            // not part of the project
+            let inside = dir
+                .canonicalize()
+                .is_ok_and(|dir| dir.starts_with(&project_dir));
+            if dir.is_dir() && inside {
+                files.extend(files_in(dir)?);
+            }
+        }
+        build_dirs.push(build_dir);
+    }
+    for file in &config.dist.files {
+        if file.is_dir() {
+            files.extend(files_in(file)?);
+        } else if file.exists() {
+            files.push(file.clone());
+        } else {
+            bail!(error!(
+                "{} is listed in [dist] but does not exist",
+                file.display()
+            ));
+        }
+    }
+
+    let mut relative_files = BTreeSet::new();
+    for file in files {
+        let file = current_dir.join(&file);
+        if build_dirs
+            .iter()
+            .any(|build_dir| file.starts_with(build_dir))
+            || file.starts_with(current_dir.join(".cbt"))
+        {
+            continue;
+        }
+        match file.strip_prefix(&current_dir) {
+            Ok(relative) => relative_files.insert(relative.to_path_buf()),
+            Err(_) => bail!(error!(
+                "{} is outside the project directory and can't be distributed",
+                file.display()
+            )),
+        };
+    }
+    Ok(relative_files)
+}
+
+/// Packs the project into `<name>-<version>.tar.gz`. Entries are sorted and
+/// carry no owner and a fixed mtime (`SOURCE_DATE_EPOCH`, or 0), so the same
+/// sources always give the same tarball.
+pub fn dist(config: &Config, config_file: &Path) -> anyhow::Result<PathBuf> {
+    let name = dist_name(config)?;
+    let tarball = PathBuf::from(format!("{}.tar.gz", name));
+    let mtime = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or(0);
+
+    let file = fs::File::create(&tarball)
+        .with_context(|| error!("Failed to create {}", tarball.display()))?;
+    let encoder = GzBuilder::new()
+        .mtime(mtime as u32)
+        .write(file, Compression::best());
+    let mut builder = tar::Builder::new(encoder);
+    for path in dist_files(config, config_file)? {
+        let contents =
+            fs::read(&path).with_context(|| error!("Could not read {}", path.display()))?;
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(contents.len() as u64);
+        header.set_mode(if is_executable(&path) { 0o755 } else { 0o644 });
+        header.set_mtime(mtime);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder
+            .append_data(
+                &mut header,
+                Path::new(&name).join(&path),
+                contents.as_slice(),
+            )
+            .with_context(|| error!("Failed to add {} to {}", path.display(), tarball.display()))?;
+    }
+    builder
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .with_context(|| error!("Failed to write {}", tarball.display()))?;
+    println!("{} {}", message!("Created"), tarball.display());
+    Ok(tarball)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    false
+}
+
+/// Unpacks `tarball` into a temporary directory and builds it there, to
+/// catch files missing from the distribution. The directory is kept if the
+/// build fails.
+pub fn distcheck(config: &Config, config_file: &Path, tarball: &Path) -> anyhow::Result<()> {
+    let dir = env::temp_dir().join(format!("cbt-distcheck-{}", process::id()));
+    let file =
+        fs::File::open(tarball).with_context(|| error!("Could not open {}", tarball.display()))?;
+    tar::Archive::new(GzDecoder::new(file))
+        .unpack(&dir)
+        .with_context(|| error!("Failed to unpack {}", tarball.display()))?;
+
+    let project = dir.join(dist_name(config)?);
+    println!("{} {}", message!("Building"), project.display());
+    let status = Command::new(env::current_exe()?)
+        .arg("build")
+        .arg("--config")
+        .arg(project.join(config_file))
+        .status()
+        .with_context(|| error!("Failed to run cbt build"))?;
+    if !status.success() {
+        bail!(error!(
+            "{} does not build on its own, the unpacked tree is left in {}",
+            tarball.display(),
+            dir.display()
+        ));
+    }
+    fs::remove_dir_all(&dir).with_context(|| error!("Failed to remove {}", dir.display()))?;
+    println!("{} {} builds", message!("Checked"), tarball.display());
+    Ok(())
+}
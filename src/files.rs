@@ -1,7 +1,14 @@
-use std::{env, fs, path::PathBuf};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
 
-use crate::{config::Stage, error};
+use crate::{
+    config::{Assembler, Extension, Extensions, Stage},
+    error,
+};
 use anyhow::bail;
+use serde_derive::{Deserialize, Serialize};
 
 #[derive(Debug)]
 pub struct SourceFile {
@@ -9,43 +16,75 @@ pub struct SourceFile {
     pub out_path: PathBuf,
     pub name: String,
     pub lang: Language,
+    /// Compiler overriding the default one for `lang`, from `[extensions]`
+    pub compiler: Option<String>,
+    /// Flags appended after the stage flags, from `[extensions]`
+    pub flags: Vec<String>,
 }
 
-#[derive(Debug)]
+impl SourceFile {
+    /// The object file for this source, keeping the whole file name so that
+    /// `foo.c` and `foo.cpp` don't both compile to `foo.o`
+    pub fn object_path(&self) -> PathBuf {
+        let mut path = self.out_path.clone().into_os_string();
+        path.push(".o");
+        PathBuf::from(path)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum Language {
+    #[serde(rename = "c")]
     C,
-    CXX,
-    ASM,
+    #[serde(rename = "cxx", alias = "c++")]
+    Cxx,
+    #[serde(rename = "asm")]
+    Asm,
+    /// Assembly that goes through the C preprocessor (`.S`), built with `cc`
+    #[serde(rename = "asm-cpp")]
+    AsmCpp,
+    /// Not compiled on its own, only tracked as part of the sources
+    #[serde(rename = "header")]
+    Header,
 }
 
-fn walk_dir(dir: &PathBuf, stage: &Stage) -> anyhow::Result<Vec<PathBuf>> {
+impl Language {
+    pub fn is_compiled(&self) -> bool {
+        !matches!(self, Language::Header)
+    }
+
+    /// The `[compilers]` key of the program that builds this language
+    pub fn compiler_role(&self, assembler: Assembler) -> &'static str {
+        match self {
+            Language::Cxx => "cxx",
+            Language::Asm if assembler != Assembler::Cc => "asm",
+            _ => "cc",
+        }
+    }
+}
+
+fn walk_dir(dir: &Path, stage: &Stage) -> anyhow::Result<Vec<PathBuf>> {
     let mut paths = Vec::new();
-    for entry in fs::read_dir(dir)? {
-        if let Ok(entry) = entry {
-            if entry.path().is_dir() {
-                let mut exclude = false;
-                for exclude_dir in &stage.exclude.dirs {
-                    if entry.path().ends_with(exclude_dir) {
-                        exclude = true;
-                    }
-                }
-                if exclude {
-                    continue;
+    for entry in fs::read_dir(dir)?.flatten() {
+        if entry.path().is_dir() {
+            let mut exclude = false;
+            for exclude_dir in &stage.exclude.dirs {
+                if entry.path().ends_with(exclude_dir) {
+                    exclude = true;
                 }
-                paths.push(entry.path());
-                paths.extend(walk_dir(&entry.path(), stage)?);
             }
+            if exclude {
+                continue;
+            }
+            paths.push(entry.path());
+            paths.extend(walk_dir(&entry.path(), stage)?);
         }
     }
     Ok(paths)
 }
 
-pub fn copy_dir_structure(
-    from_root: &PathBuf,
-    to_root: &PathBuf,
-    stage: &Stage,
-) -> anyhow::Result<()> {
-    let paths = walk_dir(&from_root, stage)?;
+pub fn copy_dir_structure(from_root: &Path, to_root: &Path, stage: &Stage) -> anyhow::Result<()> {
+    let paths = walk_dir(from_root, stage)?;
     for path in paths {
         let p: PathBuf = path
             .components()
@@ -56,6 +95,60 @@ pub fn copy_dir_structure(
     Ok(())
 }
 
+/// Removes directories under `to_root` that `copy_dir_structure` would no
+/// longer create, once they are empty, returning the removed directories
+pub fn prune_dir_structure(
+    from_root: &Path,
+    to_root: &Path,
+    stage: &Stage,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let expected: Vec<PathBuf> = walk_dir(from_root, stage)?
+        .iter()
+        .map(|path| {
+            let p: PathBuf = path
+                .components()
+                .skip(from_root.components().count())
+                .collect();
+            to_root.join(p)
+        })
+        .collect();
+    let mut removed = Vec::new();
+    // Deepest first, so parents are empty by the time they are checked
+    for dir in all_dirs(to_root)?.iter().rev() {
+        if expected.contains(dir) || fs::read_dir(dir)?.next().is_some() {
+            continue;
+        }
+        fs::remove_dir(dir)?;
+        removed.push(dir.clone());
+    }
+    Ok(removed)
+}
+
+/// Every file below `dir`, sorted
+pub fn files_in(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)?.flatten() {
+        if entry.path().is_dir() {
+            files.extend(files_in(&entry.path())?);
+        } else {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn all_dirs(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)?.flatten() {
+        if entry.path().is_dir() {
+            paths.push(entry.path());
+            paths.extend(all_dirs(&entry.path())?);
+        }
+    }
+    Ok(paths)
+}
+
 pub fn get_dirs(stage: &Stage) -> anyhow::Result<(PathBuf, PathBuf)> {
     let current_dir = env::current_dir()?;
     let src_dir = current_dir.join(&stage.source.source_dir);
@@ -63,18 +156,18 @@ pub fn get_dirs(stage: &Stage) -> anyhow::Result<(PathBuf, PathBuf)> {
     Ok((src_dir, build_dir))
 }
 
-pub fn setup_build_dir(
-    src_dir: &PathBuf,
-    build_dir: &PathBuf,
-    stage: &Stage,
-) -> anyhow::Result<()> {
+pub fn setup_build_dir(src_dir: &Path, build_dir: &Path, stage: &Stage) -> anyhow::Result<()> {
     let objects_dir = build_dir.join("objects");
     fs::create_dir_all(&objects_dir)?;
-    copy_dir_structure(&src_dir, &objects_dir, stage)?;
+    copy_dir_structure(src_dir, &objects_dir, stage)?;
     Ok(())
 }
 
-pub fn get_src_files(src_dir: &PathBuf, stage: &Stage) -> anyhow::Result<Vec<SourceFile>> {
+pub fn get_src_files(
+    src_dir: &Path,
+    stage: &Stage,
+    extensions: &Extensions,
+) -> anyhow::Result<Vec<SourceFile>> {
     let mut src_files = Vec::new();
     for entry in fs::read_dir(src_dir)? {
         let entry = entry?;
@@ -90,7 +183,7 @@ pub fn get_src_files(src_dir: &PathBuf, stage: &Stage) -> anyhow::Result<Vec<Sou
             if exclude {
                 continue;
             }
-            src_files.extend(get_src_files(&path, stage)?);
+            src_files.extend(get_src_files(&path, stage, extensions)?);
         } else {
             let filename = match path.file_name() {
                 Some(filename) => match filename.to_str() {
@@ -114,7 +207,7 @@ pub fn get_src_files(src_dir: &PathBuf, stage: &Stage) -> anyhow::Result<Vec<Sou
             let src_dir_base = PathBuf::from(&stage.source.source_dir).canonicalize()?;
             let new_path_components = path.components().skip(src_dir_base.components().count());
             let out_path = new_path_components.clone().fold(
-                PathBuf::from(&stage.build.build_dir.join("objects")),
+                stage.build.build_dir.join("objects"),
                 |mut path, comp| {
                     path.push(comp);
                     path
@@ -128,35 +221,58 @@ pub fn get_src_files(src_dir: &PathBuf, stage: &Stage) -> anyhow::Result<Vec<Sou
                 })
                 .canonicalize()?;
 
-            if let Some(ext) = path.extension() {
-                match ext.to_ascii_lowercase().to_str() {
-                    Some("c") => src_files.push(SourceFile {
-                        path: new_path,
-                        out_path,
-                        name: filename,
-                        lang: Language::C,
-                    }),
-                    Some("cpp") => src_files.push(SourceFile {
-                        path: new_path,
-                        out_path,
-                        name: filename,
-                        lang: Language::CXX,
-                    }),
-                    Some("s" | "asm") => src_files.push(SourceFile {
-                        path: new_path,
-                        out_path,
-                        name: filename,
-                        lang: Language::ASM,
-                    }),
-                    _ => continue,
-                }
-            } else {
-                continue;
-            }
+            let extension = match path.extension().and_then(|ext| ext.to_str()) {
+                Some(ext) => match extensions.get(ext) {
+                    Some(extension) => extension,
+                    None => continue,
+                },
+                None => continue,
+            };
+            let (compiler, flags) = match &extension {
+                Extension::Language(_) => (None, Vec::new()),
+                Extension::Custom {
+                    compiler, flags, ..
+                } => (compiler.clone(), flags.clone()),
+            };
+            src_files.push(SourceFile {
+                path: new_path,
+                out_path,
+                name: filename,
+                lang: extension.language(),
+                compiler,
+                flags,
+            });
         }
     }
-    if src_files.len() == 0 {
-        bail!(error!("No source files found in source directory"));
-    }
     Ok(src_files)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prunes_dirs_of_removed_sources() {
+        let root = env::temp_dir().join(format!("cbt-prune-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (src, objects) = (root.join("src"), root.join("objects"));
+        fs::create_dir_all(src.join("kept")).unwrap();
+        for dir in ["kept", "removed/nested", "other"] {
+            fs::create_dir_all(objects.join(dir)).unwrap();
+        }
+        // Another stage's output in the same tree
+        fs::write(objects.join("other").join("lib.c.o"), "object").unwrap();
+
+        let removed = prune_dir_structure(&src, &objects, &Stage::default()).unwrap();
+        assert_eq!(
+            removed,
+            [
+                objects.join("removed").join("nested"),
+                objects.join("removed")
+            ]
+        );
+        assert!(objects.join("kept").is_dir());
+        assert!(objects.join("other").join("lib.c.o").is_file());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
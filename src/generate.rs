@@ -0,0 +1,650 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::bail;
+
+use crate::{
+    config::{Config, Define, Embed, Project, Stage},
+    error,
+};
+
+/// Where a stage's generated headers go. It's on the stage's include path.
+/// Stages sharing a build dir each get their own, since check results and
+/// defines differ between them.
+pub fn generated_dir(build_dir: &Path, stage: &Stage) -> PathBuf {
+    build_dir.join("generated").join(&stage.name)
+}
+
+/// `name` turned into a C identifier, e.g. `my-lib` into `my_lib`
+pub fn identifier(name: &str) -> String {
+    let mut identifier: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if identifier.is_empty() || identifier.starts_with(|c: char| c.is_ascii_digit()) {
+        identifier.insert(0, '_');
+    }
+    identifier
+}
+
+/// `value` as a C string literal
+pub fn c_string(value: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                literal.push('\\');
+                literal.push(c);
+            }
+            '\n' => literal.push_str("\\n"),
+            _ => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// The numeric parts of a version, e.g. 1, 2 and 3 for "1.2.3-rc1"
+fn version_parts(version: &str) -> [u64; 3] {
+    let mut parts = version.split(['.', '-', '+']).map(|part| {
+        part.chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect::<String>()
+            .parse::<u64>()
+            .unwrap_or(0)
+    });
+    [(); 3].map(|_| parts.next().unwrap_or(0))
+}
+
+/// `<name>_version.h`, with the project metadata as macros prefixed with the
+/// upper-case name, so programs can print `--version` without repeating it.
+/// Projects without a name get none.
+pub fn version_header(project: &Project) -> Option<(String, String)> {
+    let name = project.name.as_deref()?;
+    let file_name = format!("{}_version.h", identifier(name).to_lowercase());
+    let prefix = identifier(name).to_uppercase();
+    let version = project.version();
+
+    let [major, minor, patch] = version_parts(version);
+    let mut macros = vec![
+        ("NAME", c_string(name)),
+        ("VERSION", c_string(version)),
+        ("VERSION_MAJOR", major.to_string()),
+        ("VERSION_MINOR", minor.to_string()),
+        ("VERSION_PATCH", patch.to_string()),
+        ("VERSION_STRING", c_string(&format!("{} {}", name, version))),
+    ];
+    if let Some(description) = &project.description {
+        macros.push(("DESCRIPTION", c_string(description)));
+    }
+    if let Some(license) = &project.license {
+        macros.push(("LICENSE", c_string(license)));
+    }
+    if !project.authors.is_empty() {
+        macros.push(("AUTHORS", c_string(&project.authors.join(", "))));
+    }
+
+    let guard = format!("{}_VERSION_H", prefix);
+    let mut header = format!(
+        "/* Generated by cbt from [project], do not edit */\n#ifndef {}\n#define {}\n\n",
+        guard, guard
+    );
+    for (macro_name, value) in macros {
+        header.push_str(&format!("#define {}_{} {}\n", prefix, macro_name, value));
+    }
+    header.push_str(&format!("\n#endif /* {} */\n", guard));
+    Some((file_name, header))
+}
+
+/// `<name>_config.h`: the version header, the git commit and whether the
+/// work tree had changes, the build profile, `[config_header.defines]` and
+/// the stage's check results. Outside a git repository the git macros are
+/// left out.
+pub fn config_header(config: &Config, stage: &Stage) -> anyhow::Result<Option<(String, String)>> {
+    let config_header = match &config.config_header {
+        Some(config_header) => config_header,
+        None => return Ok(None),
+    };
+    let name = match &config.project.name {
+        Some(name) => name,
+        None => bail!(error!("[config_header] needs a name in [project]")),
+    };
+    let file_name = format!("{}_config.h", identifier(name).to_lowercase());
+    let version_header = format!("{}_version.h", identifier(name).to_lowercase());
+    let prefix = identifier(name).to_uppercase();
+
+    let mut macros = Vec::new();
+    if let Some(commit) = git(&config.dir, &["rev-parse", "HEAD"]) {
+        let dirty = git(
+            &config.dir,
+            &["status", "--porcelain", "--untracked-files=no"],
+        )
+        .is_some_and(|status| !status.is_empty());
+        macros.push((format!("{}_GIT_COMMIT", prefix), Some(c_string(&commit))));
+        macros.push((
+            format!("{}_GIT_DIRTY", prefix),
+            Some(if dirty { "1" } else { "0" }.to_owned()),
+        ));
+    }
+    if let Some(profile) = &config.profile {
+        macros.push((format!("{}_BUILD_PROFILE", prefix), Some(c_string(profile))));
+    }
+    for (define, value) in config_header.defines.iter().chain(&stage.check_defines) {
+        macros.push((define.clone(), value.value()));
+    }
+
+    let guard = format!("{}_CONFIG_H", prefix);
+    let mut header = format!(
+        "/* Generated by cbt from [config_header], do not edit */\n#ifndef {}\n#define {}\n\n#include \"{}\"\n\n",
+        guard, guard, version_header
+    );
+    for (macro_name, value) in macros {
+        match value {
+            Some(value) => header.push_str(&format!("#define {} {}\n", macro_name, value)),
+            None => header.push_str(&format!("/* #undef {} */\n", macro_name)),
+        }
+    }
+    header.push_str(&format!("\n#endif /* {} */\n", guard));
+    Ok(Some((file_name, header)))
+}
+
+/// The trimmed output of a git command run in `dir`, or `None` if it failed,
+/// e.g. outside a repository
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// The variables a `[[configure]]` template of `stage` can use. `None` means
+/// defined as false, which `#cmakedefine` leaves undefined.
+pub fn configure_variables(config: &Config, stage: &Stage) -> BTreeMap<String, Option<String>> {
+    let project = &config.project;
+    let [major, minor, patch] = version_parts(project.version());
+    let mut variables = BTreeMap::new();
+    let metadata = [
+        ("PROJECT_NAME", project.name.clone()),
+        ("PROJECT_VERSION", Some(project.version().to_owned())),
+        ("PROJECT_VERSION_MAJOR", Some(major.to_string())),
+        ("PROJECT_VERSION_MINOR", Some(minor.to_string())),
+        ("PROJECT_VERSION_PATCH", Some(patch.to_string())),
+        ("PROJECT_DESCRIPTION", project.description.clone()),
+        ("PROJECT_LICENSE", project.license.clone()),
+        ("PROJECT_AUTHORS", Some(project.authors.join(", "))),
+        ("BUILD_PROFILE", config.profile.clone()),
+    ];
+    for (variable, value) in metadata {
+        if let Some(value) = value {
+            variables.insert(variable.to_owned(), Some(value));
+        }
+    }
+    let config_header_defines = config
+        .config_header
+        .iter()
+        .flat_map(|header| &header.defines);
+    for (define, value) in config_header_defines.chain(&stage.defines) {
+        // Templates quote strings themselves, as in CMake
+        let value = match value {
+            Define::Str(text) => Some(text.clone()),
+            _ => value.value(),
+        };
+        variables.insert(define.clone(), value);
+    }
+    variables
+}
+
+/// Fills in `template`. Unknown variables become empty, as in CMake, and
+/// are returned so they can be reported. `#cmakedefine` of an unknown
+/// variable is just false.
+pub fn configure(
+    template: &str,
+    variables: &BTreeMap<String, Option<String>>,
+) -> (String, BTreeSet<String>) {
+    let mut unknown = BTreeSet::new();
+    let mut output = String::new();
+    for line in template.split_inclusive('\n') {
+        let body = line.trim_end_matches(['\r', '\n']);
+        let ending = &line[body.len()..];
+        let indent = &body[..body.len() - body.trim_start().len()];
+        let mut words = body.trim_start().splitn(2, char::is_whitespace);
+        let directive = words.next().unwrap_or_default();
+        let rest = words.next().unwrap_or_default().trim_start();
+        let (name, value) = match rest.split_once(char::is_whitespace) {
+            Some((name, value)) => (name, value.trim_start()),
+            None => (rest, ""),
+        };
+        let truthy = match variables.get(name) {
+            Some(Some(value)) => !matches!(
+                value.to_lowercase().as_str(),
+                "" | "0" | "false" | "off" | "no" | "n" | "notfound"
+            ),
+            Some(None) | None => false,
+        };
+        match directive {
+            "#cmakedefine01" => {
+                output.push_str(&format!("{}#define {} {}", indent, name, truthy as u8))
+            }
+            "#cmakedefine" if truthy => {
+                let value = substitute(value, variables, &mut unknown);
+                output.push_str(format!("{}#define {} {}", indent, name, value).trim_end())
+            }
+            "#cmakedefine" => output.push_str(&format!("{}/* #undef {} */", indent, name)),
+            _ => output.push_str(&substitute(body, variables, &mut unknown)),
+        }
+        output.push_str(ending);
+    }
+    (output, unknown)
+}
+
+/// Replaces `@VAR@` and `${VAR}` in `text`. Anything else with an `@` or a
+/// `$`, like an email address, is left alone.
+fn substitute(
+    text: &str,
+    variables: &BTreeMap<String, Option<String>>,
+    unknown: &mut BTreeSet<String>,
+) -> String {
+    let is_name = |name: &str| {
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(['@', '$']) {
+        output.push_str(&rest[..start]);
+        let candidate = &rest[start..];
+        let reference = if let Some(after) = candidate.strip_prefix('@') {
+            after.find('@').map(|end| (&after[..end], end + 2))
+        } else if let Some(after) = candidate.strip_prefix("${") {
+            after.find('}').map(|end| (&after[..end], end + 3))
+        } else {
+            None
+        };
+        match reference {
+            Some((name, length)) if is_name(name) => {
+                match variables.get(name) {
+                    Some(value) => output.push_str(value.as_deref().unwrap_or_default()),
+                    None => {
+                        unknown.insert(name.to_owned());
+                    }
+                }
+                rest = &candidate[length..];
+            }
+            _ => {
+                output.push_str(&candidate[..1]);
+                rest = &candidate[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// The `[[embed]]`s of `stage` with their symbol names
+pub fn stage_embeds<'a>(
+    config: &'a Config,
+    stage: &Stage,
+) -> anyhow::Result<Vec<(&'a Embed, String)>> {
+    let mut embeds = Vec::new();
+    for embed in &config.embeds {
+        if embed.stage.as_ref().is_some_and(|name| *name != stage.name) {
+            continue;
+        }
+        let name = match &embed.name {
+            Some(name) if identifier(name) != *name => {
+                bail!(error!("Embed name {} is not a C identifier", name))
+            }
+            Some(name) => name.clone(),
+            None => identifier(&embed.file.file_name().unwrap_or_default().to_string_lossy()),
+        };
+        if embeds.iter().any(|(_, other)| *other == name) {
+            bail!(error!(
+                "Two embeds of stage {} are named {}",
+                stage.name, name
+            ));
+        }
+        embeds.push((embed, name));
+    }
+    Ok(embeds)
+}
+
+/// The header declaring embed `name`
+pub fn embed_header(name: &str, file: &Path) -> String {
+    let guard = format!("{}_H", name.to_uppercase());
+    format!(
+        "/* Generated by cbt from {}, do not edit */\n\
+         #ifndef {}\n#define {}\n\n\
+         #include <stddef.h>\n\n\
+         #ifdef __cplusplus\nextern \"C\" {{\n#endif\n\n\
+         extern const unsigned char {}[];\nextern const size_t {}_len;\n\n\
+         #ifdef __cplusplus\n}}\n#endif\n\n\
+         #endif /* {} */\n",
+        file.display(),
+        guard,
+        guard,
+        name,
+        name,
+        guard
+    )
+}
+
+/// The source defining embed `name` with `contents`. A NUL follows the
+/// contents, so text can be used as a C string, but isn't in `<name>_len`.
+pub fn embed_source(name: &str, file: &Path, contents: &[u8]) -> String {
+    let mut source = format!(
+        "/* Generated by cbt from {}, do not edit */\n#include \"{}.h\"\n\nconst unsigned char {}[] = {{",
+        file.display(),
+        name,
+        name
+    );
+    for (i, byte) in contents.iter().chain(&[0]).enumerate() {
+        source.push_str(if i % 12 == 0 { "\n    " } else { " " });
+        source.push_str(&format!("0x{:02x},", byte));
+    }
+    source.push_str(&format!(
+        "\n}};\n\nconst size_t {}_len = {};\n",
+        name,
+        contents.len()
+    ));
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_stage;
+
+    #[test]
+    fn version_parts_ignore_suffixes() {
+        assert_eq!(version_parts("1.2.3"), [1, 2, 3]);
+        assert_eq!(version_parts("2.0-rc1"), [2, 0, 0]);
+        assert_eq!(version_parts("1.4.2+build.7"), [1, 4, 2]);
+        assert_eq!(version_parts("3"), [3, 0, 0]);
+    }
+
+    #[test]
+    fn version_header_from_project() {
+        let project = Project {
+            name: Some("my-lib".to_owned()),
+            version: Some("1.2.3".to_owned()),
+            description: Some("Says \"hi\"".to_owned()),
+            authors: vec!["A".to_owned(), "B".to_owned()],
+            ..Default::default()
+        };
+        let (file_name, header) = version_header(&project).unwrap();
+        assert_eq!(file_name, "my_lib_version.h");
+        assert_eq!(
+            header,
+            "/* Generated by cbt from [project], do not edit */\n\
+             #ifndef MY_LIB_VERSION_H\n\
+             #define MY_LIB_VERSION_H\n\
+             \n\
+             #define MY_LIB_NAME \"my-lib\"\n\
+             #define MY_LIB_VERSION \"1.2.3\"\n\
+             #define MY_LIB_VERSION_MAJOR 1\n\
+             #define MY_LIB_VERSION_MINOR 2\n\
+             #define MY_LIB_VERSION_PATCH 3\n\
+             #define MY_LIB_VERSION_STRING \"my-lib 1.2.3\"\n\
+             #define MY_LIB_DESCRIPTION \"Says \\\"hi\\\"\"\n\
+             #define MY_LIB_AUTHORS \"A, B\"\n\
+             \n\
+             #endif /* MY_LIB_VERSION_H */\n"
+        );
+    }
+
+    #[test]
+    fn no_version_header_without_a_name() {
+        assert_eq!(version_header(&Project::default()), None);
+    }
+
+    fn header_config(dir: &Path) -> Config {
+        let mut config: Config = toml::from_str(
+            r#"
+            stage = []
+
+            [project]
+            name = "app"
+
+            [config_header.defines]
+            USE_COLOR = true
+            GREETING = "hello"
+            LEGACY = false
+            "#,
+        )
+        .unwrap();
+        config.stages.push(test_stage(""));
+        config.profile = Some("release".to_owned());
+        config.dir = dir.to_path_buf();
+        config
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cbt-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn config_header_outside_git() {
+        let dir = temp_dir("config-header");
+        let mut config = header_config(&dir);
+        config.stages[0].check_defines.insert(
+            "HAVE_UNISTD_H".to_owned(),
+            Define::Raw {
+                raw: "1".to_owned(),
+            },
+        );
+        let (file_name, header) = config_header(&config, &config.stages[0]).unwrap().unwrap();
+        assert_eq!(file_name, "app_config.h");
+        assert_eq!(
+            header,
+            "/* Generated by cbt from [config_header], do not edit */\n\
+             #ifndef APP_CONFIG_H\n\
+             #define APP_CONFIG_H\n\
+             \n\
+             #include \"app_version.h\"\n\
+             \n\
+             #define APP_BUILD_PROFILE \"release\"\n\
+             #define GREETING \"hello\"\n\
+             /* #undef LEGACY */\n\
+             #define USE_COLOR 1\n\
+             #define HAVE_UNISTD_H 1\n\
+             \n\
+             #endif /* APP_CONFIG_H */\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stages_sharing_a_build_dir_generate_apart() {
+        let dir = temp_dir("config-header-stages");
+        let mut config = header_config(&dir);
+        config.stages = vec![test_stage("app"), test_stage("lib")];
+        config.stages[1]
+            .check_defines
+            .insert("HAVE_LIBLIB".to_owned(), Define::Bool(true));
+        let [app, lib] = [&config.stages[0], &config.stages[1]];
+        assert_eq!(
+            generated_dir(Path::new("build"), app),
+            Path::new("build/generated/app")
+        );
+        assert_ne!(
+            generated_dir(Path::new("build"), app),
+            generated_dir(Path::new("build"), lib)
+        );
+        let header = |stage| config_header(&config, stage).unwrap().unwrap().1;
+        assert!(!header(app).contains("HAVE_LIBLIB"));
+        assert!(header(lib).contains("#define HAVE_LIBLIB 1"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn config_header_uses_the_project_repository() {
+        let dir = temp_dir("config-header-git");
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=cbt", "-c", "user.email=cbt@localhost"])
+                .args(args)
+                .current_dir(&dir)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {:?} failed", args);
+        };
+        git(&["init", "-q"]);
+        std::fs::write(dir.join("cbt.toml"), "").unwrap();
+        git(&["add", "cbt.toml"]);
+        git(&["commit", "-q", "-m", "init"]);
+        let commit = super::git(&dir, &["rev-parse", "HEAD"]).unwrap();
+
+        let config = header_config(&dir);
+        let (_, header) = config_header(&config, &config.stages[0]).unwrap().unwrap();
+        assert!(header.contains(&format!("#define APP_GIT_COMMIT \"{}\"\n", commit)));
+        assert!(header.contains("#define APP_GIT_DIRTY 0\n"));
+
+        std::fs::write(dir.join("cbt.toml"), "# changed").unwrap();
+        let (_, header) = config_header(&config, &config.stages[0]).unwrap().unwrap();
+        assert!(header.contains("#define APP_GIT_DIRTY 1\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn variables(pairs: &[(&str, Option<&str>)]) -> BTreeMap<String, Option<String>> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.map(|value| value.to_owned())))
+            .collect()
+    }
+
+    #[test]
+    fn substitutes_both_reference_styles() {
+        let variables = variables(&[("NAME", Some("app")), ("EMPTY", None)]);
+        let (output, unknown) = configure(
+            "@NAME@ ${NAME} [@EMPTY@] ${MISSING} me@example.com $HOME @1@ ${}\n",
+            &variables,
+        );
+        assert_eq!(output, "app app []  me@example.com $HOME @1@ ${}\n");
+        assert_eq!(unknown, BTreeSet::from(["MISSING".to_owned()]));
+    }
+
+    #[test]
+    fn cmakedefine_lines() {
+        let variables = variables(&[
+            ("HAVE_FOO", Some("1")),
+            ("HAVE_BAR", Some("OFF")),
+            ("HAVE_BAZ", None),
+            ("GREETING", Some("hello")),
+        ]);
+        let template = "#cmakedefine HAVE_FOO\n\
+                        #cmakedefine HAVE_BAR\n\
+                        #cmakedefine HAVE_BAZ 1\n\
+                        #cmakedefine HAVE_QUX\n\
+                        \x20 #cmakedefine GREETING \"@GREETING@\"\r\n\
+                        #cmakedefine01 HAVE_FOO\n\
+                        #cmakedefine01 HAVE_BAR\n\
+                        #cmakedefine01 HAVE_QUX";
+        let (output, unknown) = configure(template, &variables);
+        assert_eq!(
+            output,
+            "#define HAVE_FOO\n\
+             /* #undef HAVE_BAR */\n\
+             /* #undef HAVE_BAZ */\n\
+             /* #undef HAVE_QUX */\n\
+             \x20 #define GREETING \"hello\"\r\n\
+             #define HAVE_FOO 1\n\
+             #define HAVE_BAR 0\n\
+             #define HAVE_QUX 0"
+        );
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn configure_variables_keep_strings_unquoted() {
+        let mut config = header_config(Path::new(""));
+        config.project.version = Some("2.1.0".to_owned());
+        let variables = configure_variables(&config, &config.stages[0]);
+        assert_eq!(variables["PROJECT_NAME"].as_deref(), Some("app"));
+        assert_eq!(variables["PROJECT_VERSION_MINOR"].as_deref(), Some("1"));
+        assert_eq!(variables["BUILD_PROFILE"].as_deref(), Some("release"));
+        assert_eq!(variables["GREETING"].as_deref(), Some("hello"));
+        assert_eq!(variables["USE_COLOR"].as_deref(), Some("1"));
+        assert_eq!(variables["LEGACY"], None);
+    }
+
+    #[test]
+    fn embed_source_is_nul_terminated() {
+        let source = embed_source("logo", Path::new("assets/logo.txt"), b"hi\n");
+        assert_eq!(
+            source,
+            "/* Generated by cbt from assets/logo.txt, do not edit */\n\
+             #include \"logo.h\"\n\
+             \n\
+             const unsigned char logo[] = {\n    \
+             0x68, 0x69, 0x0a, 0x00,\n\
+             };\n\
+             \n\
+             const size_t logo_len = 3;\n"
+        );
+        let long = embed_source("data", Path::new("data.bin"), &[0xff; 12]);
+        assert!(long.contains("0xff,\n    0x00,\n};"));
+    }
+
+    fn embed_config(embeds: &str) -> Config {
+        let mut config: Config = toml::from_str(&format!("stage = []\n{}", embeds)).unwrap();
+        config.stages.push(test_stage("app"));
+        config
+    }
+
+    #[test]
+    fn stage_embed_names() {
+        let config = embed_config(
+            r#"
+            [[embed]]
+            file = "assets/index.html"
+            [[embed]]
+            file = "assets/logo.png"
+            name = "logo"
+            [[embed]]
+            file = "assets/other.bin"
+            stage = "tool"
+            "#,
+        );
+        let names: Vec<String> = stage_embeds(&config, &config.stages[0])
+            .unwrap()
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect();
+        assert_eq!(names, ["index_html", "logo"]);
+    }
+
+    #[test]
+    fn stage_embed_names_must_be_unique_identifiers() {
+        let config = embed_config(
+            r#"
+            [[embed]]
+            file = "a.bin"
+            name = "not-valid"
+            "#,
+        );
+        assert!(stage_embeds(&config, &config.stages[0]).is_err());
+
+        let config = embed_config(
+            r#"
+            [[embed]]
+            file = "one/data.bin"
+            [[embed]]
+            file = "two/data.bin"
+            "#,
+        );
+        assert!(stage_embeds(&config, &config.stages[0]).is_err());
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache::hex,
+    compilation::{executable_name, executable_path, library_path},
+    config::Config,
+    error,
+    files::{files_in, get_dirs},
+    message,
+    pkgconfig::installed_pc_file,
+    toolchain::Toolchain,
+    warning,
+};
+
+/// Everything `cbt install` wrote, so `cbt uninstall` removes exactly that.
+/// Kept in `.cbt/install-manifest` in the project directory.
+#[derive(Deserialize, Serialize, Default)]
+struct Manifest {
+    /// Directories that didn't exist before installing
+    dirs: Vec<PathBuf>,
+    /// Installed files and the hash of what was installed, so files changed
+    /// since are kept. After `dirs`, since TOML tables go last.
+    files: BTreeMap<PathBuf, String>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Manifest {
+    fn load(project_dir: &Path) -> anyhow::Result<Self> {
+        let path = project_dir.join(".cbt").join("install-manifest");
+        let manifest = match fs::read_to_string(&path) {
+            Ok(manifest) => toml::from_str(&manifest)
+                .with_context(|| error!("Failed to parse {}", path.display()))?,
+            Err(_) => Self::default(),
+        };
+        Ok(Self { path, ..manifest })
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| error!("Failed to create {}", dir.display()))?;
+        }
+        fs::write(&self.path, toml::to_string(self)?)
+            .with_context(|| error!("Failed to write {}", self.path.display()))
+    }
+
+    /// Creates `dir` and records the directories that had to be created
+    fn create_dir(&mut self, dir: &Path) -> anyhow::Result<()> {
+        let mut missing = Vec::new();
+        for ancestor in dir.ancestors() {
+            if ancestor.exists() {
+                break;
+            }
+            missing.push(ancestor.to_path_buf());
+        }
+        fs::create_dir_all(dir).with_context(|| error!("Failed to create {}", dir.display()))?;
+        for dir in missing.into_iter().rev() {
+            if !self.dirs.contains(&dir) {
+                self.dirs.push(dir);
+            }
+        }
+        Ok(())
+    }
+
+    fn install(&mut self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = to.parent() {
+            self.create_dir(dir)?;
+        }
+        println!("{} {}", message!("Installing"), to.display());
+        fs::copy(from, to)
+            .with_context(|| error!("Failed to install {} to {}", from.display(), to.display()))?;
+        self.add_file(to)
+    }
+
+    fn write(&mut self, to: &Path, contents: &str) -> anyhow::Result<()> {
+        if let Some(dir) = to.parent() {
+            self.create_dir(dir)?;
+        }
+        println!("{} {}", message!("Installing"), to.display());
+        fs::write(to, contents).with_context(|| error!("Failed to write {}", to.display()))?;
+        self.add_file(to)
+    }
+
+    fn add_file(&mut self, file: &Path) -> anyhow::Result<()> {
+        self.files.insert(file.to_path_buf(), file_hash(file)?);
+        Ok(())
+    }
+}
+
+fn file_hash(file: &Path) -> anyhow::Result<String> {
+    let contents = fs::read(file).with_context(|| error!("Failed to read {}", file.display()))?;
+    Ok(hex(&Sha256::digest(&contents)))
+}
+
+/// Copies every stage's artifacts and headers under `prefix`, staged below
+/// `destdir` if given. Paths written into installed files, like the `.pc`
+/// prefix, never include `destdir`.
+pub fn install(
+    config: &Config,
+    toolchain: &Toolchain,
+    prefix: &Path,
+    destdir: Option<&Path>,
+) -> anyhow::Result<()> {
+    if !prefix.is_absolute() {
+        bail!(error!(
+            "The install prefix {} is not absolute",
+            prefix.display()
+        ));
+    }
+    let root = match destdir {
+        Some(destdir) => destdir.join(prefix.strip_prefix("/").unwrap_or(prefix)),
+        None => prefix.to_path_buf(),
+    };
+
+    let mut manifest = Manifest::load(&config.dir)?;
+    let result = install_stages(config, toolchain, prefix, &root, &mut manifest);
+    // Record what was installed even if a later copy failed
+    manifest.save()?;
+    result
+}
+
+fn install_stages(
+    config: &Config,
+    toolchain: &Toolchain,
+    prefix: &Path,
+    root: &Path,
+    manifest: &mut Manifest,
+) -> anyhow::Result<()> {
+    for stage in &config.stages {
+        let (_, build_dir) = get_dirs(stage)?;
+        let install = &stage.install;
+
+        if install.artifact {
+            let artifact = match stage.build.library {
+                Some(kind) => {
+                    let library = library_path(kind, &build_dir, toolchain, stage)?;
+                    let pc_file = root
+                        .join("lib")
+                        .join("pkgconfig")
+                        .join(format!("lib{}.pc", stage.name));
+                    manifest.write(
+                        &pc_file,
+                        &installed_pc_file(kind, stage, &config.project, prefix),
+                    )?;
+                    Some((library, "lib"))
+                }
+                None if stage.build.build_executable => {
+                    let executable_name = executable_name(&config.project, stage);
+                    Some((executable_path(executable_name, &build_dir, stage)?, "bin"))
+                }
+                None => None,
+            };
+            if let Some((artifact, dir)) = artifact {
+                if !artifact.exists() {
+                    bail!(error!(
+                        "{} has not been built, run cbt build first",
+                        artifact.display()
+                    ));
+                }
+                let file_name = artifact.file_name().unwrap_or_default();
+                manifest.install(&artifact, &root.join(dir).join(file_name))?;
+            }
+        }
+
+        let include_dir = match &install.include_subdir {
+            Some(subdir) => root.join("include").join(subdir),
+            None => root.join("include"),
+        };
+        for headers in &install.headers {
+            if !headers.is_dir() {
+                bail!(error!(
+                    "Header directory {} of stage {} does not exist",
+                    headers.display(),
+                    stage.name
+                ));
+            }
+            for header in files_in(headers)? {
+                let relative = header.strip_prefix(headers).unwrap_or(&header);
+                manifest.install(&header, &include_dir.join(relative))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes every file in the install manifest of the project in
+/// `project_dir`, unless it changed since, then the directories installing
+/// created, if nothing else was put in them since
+pub fn uninstall(project_dir: &Path) -> anyhow::Result<()> {
+    let manifest = Manifest::load(project_dir)?;
+    if manifest.files.is_empty() && manifest.dirs.is_empty() {
+        println!("{}: nothing is installed", message!("Uninstall"));
+        return Ok(());
+    }
+    for (file, hash) in &manifest.files {
+        if !file.is_file() {
+            continue;
+        }
+        if file_hash(file)? != *hash {
+            println!(
+                "{}: {} changed since it was installed, keeping it",
+                warning!("Warning"),
+                file.display()
+            );
+            continue;
+        }
+        println!("{} {}", message!("Removing"), file.display());
+        fs::remove_file(file).with_context(|| error!("Failed to remove {}", file.display()))?;
+    }
+    for dir in manifest.dirs.iter().rev() {
+        let empty = fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_none());
+        if empty {
+            fs::remove_dir(dir).with_context(|| error!("Failed to remove {}", dir.display()))?;
+        }
+    }
+    fs::remove_file(&manifest.path)
+        .with_context(|| error!("Failed to remove {}", manifest.path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{test_stage, Compilers};
+    use std::env;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cbt-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A project with a built executable `app` and headers in `include/`
+    fn project(dir: &Path) -> Config {
+        let mut stage = test_stage("app");
+        stage.build.executable = Some("app".to_owned());
+        stage.install.include_subdir = Some(PathBuf::from("app"));
+        stage.build.build_dir = dir.join("build");
+        stage.install.headers = vec![dir.join("include")];
+        fs::create_dir_all(dir.join("build")).unwrap();
+        fs::write(dir.join("build").join("app"), "binary").unwrap();
+        fs::create_dir_all(dir.join("include").join("detail")).unwrap();
+        fs::write(dir.join("include").join("app.h"), "#pragma once").unwrap();
+        fs::write(dir.join("include").join("detail").join("impl.h"), "").unwrap();
+        Config {
+            stages: vec![stage],
+            dir: dir.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn install_records_files_under_destdir() {
+        let dir = temp_dir("install");
+        let config = project(&dir);
+        let toolchain = Toolchain::new(Compilers::default());
+        let destdir = dir.join("stage");
+        install(&config, &toolchain, Path::new("/usr/local"), Some(&destdir)).unwrap();
+
+        let root = destdir.join("usr").join("local");
+        let manifest = Manifest::load(&dir).unwrap();
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            [
+                &root.join("bin").join("app"),
+                &root.join("include").join("app").join("app.h"),
+                &root
+                    .join("include")
+                    .join("app")
+                    .join("detail")
+                    .join("impl.h"),
+            ]
+        );
+        assert_eq!(manifest.dirs.first(), Some(&destdir));
+        assert!(manifest
+            .dirs
+            .contains(&root.join("include").join("app").join("detail")));
+        assert!(manifest.files.keys().all(|file| file.is_file()));
+        assert!(install(&config, &toolchain, Path::new("usr"), None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn uninstall_removes_what_was_installed() {
+        let dir = temp_dir("uninstall");
+        let config = project(&dir);
+        let toolchain = Toolchain::new(Compilers::default());
+        let prefix = dir.join("prefix");
+        fs::create_dir_all(prefix.join("bin")).unwrap();
+        fs::write(prefix.join("bin").join("other"), "not ours").unwrap();
+        install(&config, &toolchain, &prefix, None).unwrap();
+
+        // Changed by the user after installing
+        let header = prefix.join("include").join("app").join("app.h");
+        fs::write(
+            &header,
+            "#pragma once
+#define LOCAL 1",
+        )
+        .unwrap();
+        uninstall(&dir).unwrap();
+
+        assert!(!prefix.join("bin").join("app").exists());
+        assert!(prefix.join("bin").join("other").is_file());
+        assert!(header.is_file());
+        // detail/ was emptied and pruned, include/app/ still has the header
+        assert!(!prefix.join("include").join("app").join("detail").exists());
+        assert!(prefix.join("include").join("app").is_dir());
+        assert!(!dir.join(".cbt").join("install-manifest").exists());
+
+        fs::remove_file(&header).unwrap();
+        install(&config, &toolchain, &prefix, None).unwrap();
+        uninstall(&dir).unwrap();
+        assert!(!header.exists());
+        assert!(!prefix.join("include").join("app").join("detail").exists());
+        // include/app/ was there before this install
+        assert!(prefix.join("include").join("app").is_dir());
+        assert!(prefix.join("bin").is_dir());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
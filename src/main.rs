@@ -2,19 +2,62 @@ use std::process::ExitCode;
 
 use clap::Parser;
 
+mod cache;
+mod checks;
 mod cli;
 mod commands;
 mod compilation;
 mod config;
+mod db;
+mod dist;
 mod files;
+mod generate;
+mod install;
 mod logging;
+mod pkgconfig;
+mod remote;
+mod toolchain;
 mod util;
 
 pub fn run() -> anyhow::Result<()> {
     let args = cli::Cli::parse();
     match args.subcommand {
-        cli::Commands::Build { config } => commands::build(config),
-        cli::Commands::Clean => todo!(),
+        cli::Commands::Build {
+            config,
+            profile,
+            target,
+            toolchain,
+            defines,
+        } => commands::build(config, profile, target, toolchain, defines),
+        cli::Commands::Toolchain {
+            config,
+            target,
+            toolchain,
+        } => commands::toolchain(config, target, toolchain),
+        cli::Commands::Cache { action } => match action {
+            cli::CacheAction::Stats { config } => commands::cache_stats(config),
+            cli::CacheAction::Clear { config } => commands::cache_clear(config),
+            cli::CacheAction::Serve { config, dir, bind } => {
+                commands::cache_serve(config, dir, bind)
+            }
+        },
+        cli::Commands::Explain {
+            config,
+            target,
+            output,
+        } => commands::explain(config, target, output),
+        cli::Commands::Install {
+            config,
+            profile,
+            target,
+            toolchain,
+            defines,
+            prefix,
+        } => commands::install(config, profile, target, toolchain, defines, prefix),
+        cli::Commands::Uninstall { config } => commands::uninstall(config),
+        cli::Commands::Dist { config } => commands::dist(config),
+        cli::Commands::Distcheck { config } => commands::distcheck(config),
+        cli::Commands::Clean { config, target } => commands::clean(config, target),
         cli::Commands::GenConfig { path } => commands::gen_config(path),
         cli::Commands::GenCompletions { shell } => commands::gen_completions(shell),
     }
@@ -25,7 +68,7 @@ fn main() -> ExitCode {
 
     match result {
         Ok(_) => {
-            println!("");
+            println!();
             ExitCode::SUCCESS
         }
         Err(e) => {
@@ -0,0 +1,421 @@
+use std::{
+    env,
+    ffi::OsString,
+    path::Path,
+    process::{Command, Output},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    compilation::define_args,
+    config::{LibraryKind, Project, Stage},
+    error,
+};
+
+/// Compile and link flags for a package found through pkg-config
+pub struct Package {
+    pub version: String,
+    pub cflags: Vec<String>,
+    pub libs: Vec<String>,
+}
+
+/// Resolves `spec`, a pkg-config module optionally followed by version
+/// constraints, e.g. `"zlib >= 1.2"`. `PKG_CONFIG` picks the program. With a
+/// sysroot only its `.pc` files are searched, unless `PKG_CONFIG_LIBDIR` is
+/// set, and its paths are prefixed with it.
+pub fn resolve(
+    dependency: &str,
+    spec: &str,
+    sysroot: Option<&Path>,
+    target: Option<&str>,
+) -> anyhow::Result<Package> {
+    let module = match spec.split_whitespace().next() {
+        Some(module) => module,
+        None => bail!(error!(
+            "Dependency {} has an empty pkg_config module",
+            dependency
+        )),
+    };
+    // Only the sysroot's .pc files are searched
+    let libdir = sysroot.map(|sysroot| match env::var_os("PKG_CONFIG_LIBDIR") {
+        Some(libdir) => libdir,
+        None => sysroot_libdir(sysroot, target),
+    });
+    let run = |args: &[&str]| -> anyhow::Result<Output> {
+        let program = env::var("PKG_CONFIG").unwrap_or("pkg-config".to_owned());
+        let mut cmd = Command::new(&program);
+        cmd.args(args);
+        if let (Some(sysroot), Some(libdir)) = (sysroot, &libdir) {
+            cmd.env("PKG_CONFIG_SYSROOT_DIR", sysroot);
+            cmd.env("PKG_CONFIG_LIBDIR", libdir);
+        }
+        cmd.output().with_context(|| {
+            error!(
+                "Could not run {} for dependency {}. Install pkg-config or set PKG_CONFIG",
+                program, dependency
+            )
+        })
+    };
+
+    let output = run(&["--modversion", module])?;
+    if !output.status.success() {
+        if let (Some(sysroot), Some(libdir)) = (sysroot, &libdir) {
+            bail!(error!(
+                "Could not find {}.pc for dependency {} in sysroot {}. Install its development package into the sysroot, or set PKG_CONFIG_LIBDIR to the directories containing {}.pc (currently {})",
+                module,
+                dependency,
+                sysroot.display(),
+                module,
+                libdir.to_string_lossy()
+            ));
+        }
+        let search_path = match env::var("PKG_CONFIG_PATH") {
+            Ok(path) if !path.is_empty() => path,
+            _ => "not set".to_owned(),
+        };
+        bail!(error!(
+            "Could not find {}.pc for dependency {}. Install its development package, or add the directory containing {}.pc to PKG_CONFIG_PATH (currently {})",
+            module,
+            dependency,
+            module,
+            search_path
+        ));
+    }
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+
+    if spec.trim() != module && !run(&["--exists", spec])?.status.success() {
+        bail!(error!(
+            "Dependency {} needs {}, but {} is installed",
+            dependency,
+            spec.trim(),
+            version
+        ));
+    }
+
+    let flags = |option: &str| -> anyhow::Result<Vec<String>> {
+        let output = run(&[option, module])?;
+        if !output.status.success() {
+            bail!(error!(
+                "pkg-config {} {} failed: {}",
+                option,
+                module,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(split_flags(&String::from_utf8_lossy(&output.stdout)))
+    };
+    Ok(Package {
+        version,
+        cflags: flags("--cflags")?,
+        libs: flags("--libs")?,
+    })
+}
+
+/// The target's `.pc` directories in `sysroot`, so the host's are never
+/// picked up
+fn sysroot_libdir(sysroot: &Path, target: Option<&str>) -> OsString {
+    let mut dirs = Vec::new();
+    if let Some(target) = target {
+        dirs.push(sysroot.join("usr/lib").join(target).join("pkgconfig"));
+    }
+    dirs.push(sysroot.join("usr/lib/pkgconfig"));
+    dirs.push(sysroot.join("usr/share/pkgconfig"));
+    env::join_paths(dirs).unwrap_or_default()
+}
+
+/// Splits pkg-config output into flags the way a shell would, so quoted or
+/// backslash-escaped paths with spaces stay one flag
+fn split_flags(output: &str) -> Vec<String> {
+    let mut flags = Vec::new();
+    let mut current = String::new();
+    let mut in_flag = false;
+    let mut quote = None;
+    let mut chars = output.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '\\') | (Some('"'), '\\') => {
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+                in_flag = true;
+            }
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_flag = true;
+            }
+            (Some(open), _) if c == open => quote = None,
+            (None, c) if c.is_whitespace() => {
+                if in_flag {
+                    flags.push(std::mem::take(&mut current));
+                    in_flag = false;
+                }
+            }
+            _ => {
+                current.push(c);
+                in_flag = true;
+            }
+        }
+    }
+    if in_flag {
+        flags.push(current);
+    }
+    flags
+}
+
+/// Backslash-escapes `flag` for pkg-config, which splits fields like a shell.
+/// `$`, `{` and `}` are left alone for `${variable}` references.
+fn shell_escape(flag: &str) -> String {
+    let mut escaped = String::new();
+    for c in flag.chars() {
+        if !c.is_ascii_alphanumeric() && !"-_./=:+,@%${}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The `.pc` file for library stage `stage` once installed under `prefix`
+pub fn installed_pc_file(
+    kind: LibraryKind,
+    stage: &Stage,
+    project: &Project,
+    prefix: &Path,
+) -> String {
+    let include_dir = match &stage.install.include_subdir {
+        Some(subdir) => format!("${{includedir}}/{}", subdir.display()),
+        None => "${includedir}".to_owned(),
+    };
+    pc_file(
+        &stage.name,
+        kind,
+        stage,
+        project,
+        &[
+            ("prefix", prefix.display().to_string()),
+            ("exec_prefix", "${prefix}".to_owned()),
+            ("libdir", "${exec_prefix}/lib".to_owned()),
+            ("includedir", "${prefix}/include".to_owned()),
+        ],
+        &[include_dir],
+    )
+}
+
+/// The `.pc` file for library stage `stage`, built as `lib<name>`. The
+/// version and description fall back to the project's. `variables` differ
+/// between the installed and the uninstalled file, and must define
+/// `libdir`; `include_dirs` go into `Cflags`.
+pub fn pc_file(
+    name: &str,
+    kind: LibraryKind,
+    stage: &Stage,
+    project: &Project,
+    variables: &[(&str, String)],
+    include_dirs: &[String],
+) -> String {
+    let mut pc = String::new();
+    for (variable, value) in variables {
+        pc.push_str(&format!("{}={}\n", variable, value));
+    }
+    pc.push('\n');
+    pc.push_str(&format!("Name: {}\n", name));
+    match &project.description {
+        Some(description) => pc.push_str(&format!("Description: {}\n", description)),
+        None => pc.push_str(&format!("Description: The {} library\n", name)),
+    }
+    let version = match &stage.build.version {
+        Some(version) => version,
+        None => project.version(),
+    };
+    pc.push_str(&format!("Version: {}\n", version));
+
+    // Consumers of a static library link its dependencies themselves
+    let requires: Vec<&str> = stage
+        .dependencies
+        .values()
+        .map(|dependency| dependency.pkg_config.trim())
+        .collect();
+    // Libraries given by path are linked from where the build found them
+    let private_libs: Vec<String> = stage
+        .libraries
+        .libs
+        .iter()
+        .map(|library| match library.path() {
+            Some(path) => {
+                let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+                shell_escape(&path.display().to_string())
+            }
+            None => format!("-l{}", library.name()),
+        })
+        .collect();
+    let requires_field = match kind {
+        LibraryKind::Static => "Requires",
+        LibraryKind::Shared => "Requires.private",
+    };
+    if !requires.is_empty() {
+        pc.push_str(&format!("{}: {}\n", requires_field, requires.join(", ")));
+    }
+
+    let mut cflags: Vec<String> = include_dirs
+        .iter()
+        .map(|dir| shell_escape(&format!("-I{}", dir)))
+        .collect();
+    // Build profiles, --define and checks only describe this build
+    cflags.extend(
+        define_args(&stage.config_defines)
+            .iter()
+            .map(|arg| shell_escape(arg)),
+    );
+    pc.push_str(&format!("Cflags: {}\n", cflags.join(" ")));
+    let mut libs = format!("-L${{libdir}} -l{}", name);
+    if kind == LibraryKind::Static && !private_libs.is_empty() {
+        libs.push(' ');
+        libs.push_str(&private_libs.join(" "));
+    }
+    pc.push_str(&format!("Libs: {}\n", libs));
+    if kind == LibraryKind::Shared && !private_libs.is_empty() {
+        pc.push_str(&format!("Libs.private: {}\n", private_libs.join(" ")));
+    }
+    pc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_stage;
+
+    #[test]
+    fn flags_split_like_a_shell() {
+        assert_eq!(
+            split_flags("-I/usr/include/foo  -DX=1\n"),
+            ["-I/usr/include/foo", "-DX=1"]
+        );
+        assert_eq!(
+            split_flags(r#"-I/opt/my\ sdk/include '-I/a b' "-DNAME=\"x y\"" -DE="""#),
+            ["-I/opt/my sdk/include", "-I/a b", "-DNAME=\"x y\"", "-DE="]
+        );
+        assert_eq!(split_flags("''"), [""]);
+        assert!(split_flags(" \n").is_empty());
+    }
+
+    fn library_stage() -> Stage {
+        let mut stage = test_stage("foo");
+        stage.defines = toml::from_str(
+            r#"FOO_SHARED = true
+            FOO_NAME = "foo"
+            FOO_FLAGS = { raw = "(1 << 4)" }"#,
+        )
+        .unwrap();
+        stage.libraries = toml::from_str(r#"libs = ["m", "vendor/libbar.a"]"#).unwrap();
+        stage.dependencies = toml::from_str(r#"zlib = { pkg_config = "zlib >= 1.2" }"#).unwrap();
+        stage.build.build_executable = false;
+        stage.build.version = Some("1.2.0".to_owned());
+        stage.config_defines = stage.defines.clone();
+        // Added by a profile, which must not leak into the .pc file
+        stage
+            .defines
+            .insert("NDEBUG".to_owned(), crate::config::Define::Bool(true));
+        stage
+    }
+
+    #[test]
+    fn static_pc_file() {
+        let project = Project {
+            description: Some("Foo things".to_owned()),
+            ..Default::default()
+        };
+        let pc = pc_file(
+            "foo",
+            LibraryKind::Static,
+            &library_stage(),
+            &project,
+            &[
+                ("prefix", "/usr".to_owned()),
+                ("libdir", "${prefix}/lib".to_owned()),
+            ],
+            &["${prefix}/include".to_owned(), "/opt/my sdk".to_owned()],
+        );
+        let vendored = std::path::absolute("vendor/libbar.a").unwrap();
+        assert_eq!(
+            pc,
+            format!(
+                "prefix=/usr\n\
+                 libdir=${{prefix}}/lib\n\
+                 \n\
+                 Name: foo\n\
+                 Description: Foo things\n\
+                 Version: 1.2.0\n\
+                 Requires: zlib >= 1.2\n\
+                 Cflags: -I${{prefix}}/include -I/opt/my\\ sdk -DFOO_FLAGS=\\(1\\ \\<\\<\\ 4\\) \
+                 -DFOO_NAME=\\\"foo\\\" -DFOO_SHARED=1\n\
+                 Libs: -L${{libdir}} -lfoo -lm {}\n",
+                vendored.display()
+            )
+        );
+        // pkg-config reads the flags back unchanged
+        let cflags = pc.lines().find_map(|line| line.strip_prefix("Cflags: "));
+        assert_eq!(
+            split_flags(cflags.unwrap()),
+            [
+                "-I${prefix}/include",
+                "-I/opt/my sdk",
+                "-DFOO_FLAGS=(1 << 4)",
+                "-DFOO_NAME=\"foo\"",
+                "-DFOO_SHARED=1"
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_pc_file_keeps_dependencies_private() {
+        let mut stage = library_stage();
+        stage.build.version = None;
+        let pc = pc_file(
+            "foo",
+            LibraryKind::Shared,
+            &stage,
+            &Project::default(),
+            &[("libdir", "/build".to_owned())],
+            &[],
+        );
+        assert!(pc.contains("Description: The foo library\n"));
+        assert!(pc.contains("Version: 0.0.0\n"));
+        assert!(pc.contains("Requires.private: zlib >= 1.2\n"));
+        assert!(pc.contains("Libs: -L${libdir} -lfoo\n"));
+        assert!(pc.contains("Libs.private: -lm /"));
+        assert!(pc.contains("/vendor/libbar.a\n"));
+        assert!(!pc.contains("NDEBUG"));
+    }
+
+    #[test]
+    fn sysroot_libdir_prefers_the_target() {
+        let libdir = sysroot_libdir(Path::new("/sysroot"), Some("aarch64-linux-gnu"));
+        assert_eq!(
+            env::split_paths(&libdir).collect::<Vec<_>>(),
+            [
+                Path::new("/sysroot/usr/lib/aarch64-linux-gnu/pkgconfig"),
+                Path::new("/sysroot/usr/lib/pkgconfig"),
+                Path::new("/sysroot/usr/share/pkgconfig"),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_package_in_sysroot_names_the_search_path() {
+        let sysroot = Path::new("/no/such/sysroot");
+        let error = match resolve("zlib", "cbt-no-such-module", Some(sysroot), None) {
+            Ok(_) => panic!("the module shouldn't be found"),
+            Err(e) => format!("{:#}", e),
+        };
+        assert!(error.contains("in sysroot /no/such/sysroot"), "{}", error);
+        if env::var_os("PKG_CONFIG_LIBDIR").is_none() {
+            assert!(
+                error.contains("/no/such/sysroot/usr/lib/pkgconfig"),
+                "{}",
+                error
+            );
+        }
+        assert!(!error.contains("PKG_CONFIG_PATH"), "{}", error);
+    }
+}
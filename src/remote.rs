@@ -0,0 +1,365 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use sha2::{Digest, Sha256};
+
+use crate::{cache::hex, error, info, message};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Far above any object file, but low enough to refuse bogus lengths
+const MAX_BODY: u64 = 1 << 30;
+
+/// A remote object cache speaking the bazel-remote simple HTTP layout. The
+/// object itself is stored as a content-addressed blob under
+/// `/cas/<sha256 of object>`, and `/ac/<cache key>` holds that blob's hash.
+/// bazel-remote only accepts this with `--disable_http_ac_validation`.
+pub struct RemoteCache {
+    pub url: String,
+    host: String,
+    port: u16,
+    base_path: String,
+    pub read_only: bool,
+}
+
+impl RemoteCache {
+    /// Only plain `http://` URLs are supported
+    pub fn new(url: &str, read_only: bool) -> anyhow::Result<Self> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => bail!(error!(
+                "Remote cache URL {} is not supported, only http:// URLs are",
+                url
+            )),
+        };
+        let (authority, base_path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse::<u16>()
+                    .with_context(|| error!("Invalid port in remote cache URL {}", url))?,
+            ),
+            None => (authority, 80),
+        };
+        Ok(Self {
+            url: url.to_owned(),
+            host: host.to_owned(),
+            port,
+            base_path: base_path.to_owned(),
+            read_only,
+        })
+    }
+
+    /// Fetches the object stored for `key`, if the remote has one
+    pub fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let digest = match self.request("GET", &format!("ac/{}", key), None)? {
+            (200, digest) => String::from_utf8_lossy(&digest).trim().to_owned(),
+            (404, _) => return Ok(None),
+            (status, _) => bail!("GET /ac/{} returned {}", key, status),
+        };
+        if !is_hash(&digest) {
+            bail!("/ac/{} does not contain a sha256 hash", key);
+        }
+        let object = match self.request("GET", &format!("cas/{}", digest), None)? {
+            (200, object) => object,
+            (404, _) => return Ok(None),
+            (status, _) => bail!("GET /cas/{} returned {}", digest, status),
+        };
+        // Never trust a blob that doesn't match its address
+        if hex(&Sha256::digest(&object)) != digest {
+            bail!("/cas/{} does not match its hash", digest);
+        }
+        Ok(Some(object))
+    }
+
+    pub fn put(&self, key: &str, object: &[u8]) -> anyhow::Result<()> {
+        let digest = hex(&Sha256::digest(object));
+        for (path, body) in [
+            (format!("cas/{}", digest), object),
+            (format!("ac/{}", key), digest.as_bytes()),
+        ] {
+            match self.request("PUT", &path, Some(body))? {
+                (200..=299, _) => (),
+                (status, _) => bail!("PUT /{} returned {}", path, status),
+            }
+        }
+        Ok(())
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&[u8]>,
+    ) -> anyhow::Result<(u16, Vec<u8>)> {
+        let address = match (self.host.as_str(), self.port).to_socket_addrs()?.next() {
+            Some(address) => address,
+            None => bail!("Could not resolve {}", self.host),
+        };
+        let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)
+            .with_context(|| format!("Could not connect to {}:{}", self.host, self.port))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let body = body.unwrap_or(&[]);
+        write!(
+            stream,
+            "{} {}/{} HTTP/1.1\r\nHost: {}:{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            self.base_path,
+            path,
+            self.host,
+            self.port,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let (status_line, headers) = read_head(&mut reader)?;
+        let status = match status_line.split_whitespace().nth(1) {
+            Some(status) => status.parse::<u16>()?,
+            None => bail!("Malformed HTTP response: {}", status_line),
+        };
+        let body = read_body(&mut reader, &headers, true)?;
+        Ok((status, body))
+    }
+}
+
+fn is_hash(text: &str) -> bool {
+    text.len() == 64 && text.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Reads the start line and headers, with header names lowercased
+fn read_head(reader: &mut impl BufRead) -> anyhow::Result<(String, HashMap<String, String>)> {
+    let mut start_line = String::new();
+    reader.read_line(&mut start_line)?;
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+        }
+    }
+    Ok((start_line.trim_end().to_owned(), headers))
+}
+
+/// Reads a body framed by `Content-Length` or chunked encoding. Responses
+/// without either run until the connection closes. Bodies over `MAX_BODY`
+/// are refused, so a peer can't make us allocate without bound.
+fn read_body(
+    reader: &mut impl BufRead,
+    headers: &HashMap<String, String>,
+    until_close: bool,
+) -> anyhow::Result<Vec<u8>> {
+    let too_large = |length: u64| {
+        if length > MAX_BODY {
+            bail!(
+                "Body of {} bytes is over the {} byte limit",
+                length,
+                MAX_BODY
+            )
+        }
+        Ok(())
+    };
+    let mut body = Vec::new();
+    if let Some(length) = headers.get("content-length") {
+        let length = length.parse::<u64>()?;
+        too_large(length)?;
+        reader.take(length).read_to_end(&mut body)?;
+        if body.len() as u64 != length {
+            bail!("Connection closed after {} of {} bytes", body.len(), length);
+        }
+    } else if headers
+        .get("transfer-encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size)?;
+            let size = size.trim().split(';').next().unwrap_or("");
+            let size = u64::from_str_radix(size, 16)?;
+            too_large((body.len() as u64).saturating_add(size))?;
+            let mut chunk = vec![0; size as usize + 2];
+            reader.read_exact(&mut chunk)?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size as usize]);
+        }
+    } else if until_close {
+        reader.take(MAX_BODY + 1).read_to_end(&mut body)?;
+        too_large(body.len() as u64)?;
+    }
+    Ok(body)
+}
+
+/// A minimal stand-in for bazel-remote, for trying out the remote cache
+/// locally. Blobs are kept under `dir/ac` and `dir/cas`.
+pub fn serve(dir: &Path, bind: &str) -> anyhow::Result<()> {
+    let listener =
+        TcpListener::bind(bind).with_context(|| error!("Could not listen on {}", bind))?;
+    println!(
+        "{} {} on http://{}",
+        message!("Serving"),
+        dir.display(),
+        listener.local_addr()?
+    );
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let dir = dir.to_path_buf();
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &dir) {
+                eprintln!("{}: {:?}", error!("Request failed"), e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle(stream: TcpStream, dir: &Path) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let (request_line, headers) = read_head(&mut reader)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    let (status, body) = match blob_path(dir, path) {
+        None => (400, Vec::new()),
+        Some((kind, hash, file)) => match method {
+            "GET" | "HEAD" => match fs::read(&file) {
+                Ok(blob) => (200, blob),
+                Err(_) => (404, Vec::new()),
+            },
+            "PUT" => {
+                let blob = read_body(&mut reader, &headers, false)?;
+                if kind == "cas" && hex(&Sha256::digest(&blob)) != hash {
+                    (400, Vec::new())
+                } else {
+                    store(&file, &blob)?;
+                    (200, Vec::new())
+                }
+            }
+            _ => (405, Vec::new()),
+        },
+    };
+    println!("{} {} {}", info!("{}", method), path, status);
+
+    let mut stream = stream;
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Method Not Allowed",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    )?;
+    if method != "HEAD" {
+        stream.write_all(&body)?;
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// Maps `/ac/<hash>` and `/cas/<hash>` (optionally behind a prefix) to a
+/// file in `dir`
+fn blob_path(dir: &Path, path: &str) -> Option<(&'static str, String, PathBuf)> {
+    let mut segments = path.rsplit('/');
+    let hash = segments.next()?;
+    let kind = match segments.next()? {
+        "ac" => "ac",
+        "cas" => "cas",
+        _ => return None,
+    };
+    if !is_hash(hash) {
+        return None;
+    }
+    let file = dir.join(kind).join(&hash[..2]).join(hash);
+    Some((kind, hash.to_owned(), file))
+}
+
+fn store(file: &Path, blob: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = file.with_extension(format!("tmp{:?}", thread::current().id()));
+    fs::write(&temp, blob)?;
+    fs::rename(&temp, file)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(raw: &[u8], headers: &[(&str, &str)], until_close: bool) -> anyhow::Result<Vec<u8>> {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        read_body(&mut &raw[..], &headers, until_close)
+    }
+
+    #[test]
+    fn body_with_content_length() {
+        assert_eq!(
+            body(b"hello, world", &[("content-length", "5")], false).unwrap(),
+            b"hello"
+        );
+        assert!(body(b"hel", &[("content-length", "5")], false).is_err());
+    }
+
+    #[test]
+    fn chunked_body() {
+        let raw = b"5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n";
+        assert_eq!(
+            body(raw, &[("transfer-encoding", "chunked")], false).unwrap(),
+            b"hello, world"
+        );
+    }
+
+    #[test]
+    fn body_until_close() {
+        assert_eq!(body(b"all of it", &[], true).unwrap(), b"all of it");
+        assert_eq!(body(b"ignored", &[], false).unwrap(), b"");
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused() {
+        let huge = (MAX_BODY + 1).to_string();
+        assert!(body(b"", &[("content-length", &huge)], false).is_err());
+        assert!(body(
+            b"ffffffffff\r\n",
+            &[("transfer-encoding", "chunked")],
+            false
+        )
+        .is_err());
+    }
+}
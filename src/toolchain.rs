@@ -0,0 +1,708 @@
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    env,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    config::{Compilers, Libraries, Library, LinkPreference, OptLevel, Settings, Warnings},
+    error,
+    files::Language,
+    warning,
+};
+
+/// Compiler families that take different flag spellings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Gcc,
+    Clang,
+    /// `cl` and `clang-cl`
+    Msvc,
+    Other,
+}
+
+impl Family {
+    /// Guesses the family from the program name alone. Cross prefixes and
+    /// version suffixes are ignored, e.g. `x86_64-linux-gnu-gcc-13` is GCC.
+    pub fn from_program(program: &str) -> Family {
+        let name = match Path::new(program)
+            .file_stem()
+            .and_then(|name| name.to_str())
+        {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return Family::Other,
+        };
+        let mut parts: Vec<&str> = name.split('-').collect();
+        while parts.len() > 1
+            && parts
+                .last()
+                .is_some_and(|part| part.chars().all(|c| c.is_ascii_digit() || c == '.'))
+        {
+            parts.pop();
+        }
+        match parts.as_slice() {
+            [.., "clang", "cl"] | ["cl"] => Family::Msvc,
+            [.., "clang" | "clang++"] => Family::Clang,
+            [.., "gcc" | "g++" | "cc" | "c++"] => Family::Gcc,
+            _ => Family::Other,
+        }
+    }
+}
+
+/// Translates the abstract stage settings into flags for `family`
+pub fn settings_flags(family: Family, lang: Language, settings: &Settings) -> Vec<String> {
+    let mut flags = Vec::new();
+    let msvc = family == Family::Msvc;
+
+    let std = match lang {
+        Language::C => settings.c_std.as_ref().map(|std| normalize_std(std, "c")),
+        Language::Cxx => settings
+            .cxx_std
+            .as_ref()
+            .map(|std| normalize_std(std, "c++")),
+        _ => None,
+    };
+    if let Some(std) = std {
+        if msvc {
+            flags.push(format!("/std:{}", std));
+        } else {
+            flags.push(format!("-std={}", std));
+        }
+    }
+
+    if let Some(level) = &settings.opt_level {
+        let level = match level {
+            OptLevel::Level(level) => level.to_string(),
+            OptLevel::Named(level) => level.clone(),
+        };
+        match (msvc, level.as_str()) {
+            (true, "0") => flags.push("/Od".to_owned()),
+            (true, "1" | "s" | "z") => flags.push("/O1".to_owned()),
+            (true, _) => flags.push("/O2".to_owned()),
+            (false, level) => flags.push(format!("-O{}", level)),
+        }
+    }
+
+    if settings.debug_info == Some(true) {
+        flags.push(if msvc { "/Zi" } else { "-g" }.to_owned());
+    }
+
+    if let Some(warnings) = settings.warnings {
+        let warning_flags: &[&str] = match (msvc, warnings) {
+            (_, Warnings::Default) => &[],
+            (true, Warnings::None) => &["/W0"],
+            (true, Warnings::All) => &["/W3"],
+            (true, Warnings::Extra) => &["/W4"],
+            (true, Warnings::Pedantic) => &["/Wall"],
+            (false, Warnings::None) => &["-w"],
+            (false, Warnings::All) => &["-Wall"],
+            (false, Warnings::Extra) => &["-Wall", "-Wextra"],
+            (false, Warnings::Pedantic) => &["-Wall", "-Wextra", "-Wpedantic"],
+        };
+        flags.extend(warning_flags.iter().map(|flag| flag.to_string()));
+    }
+
+    if settings.warnings_as_errors == Some(true) {
+        flags.push(if msvc { "/WX" } else { "-Werror" }.to_owned());
+    }
+
+    flags
+}
+
+/// Whose linker conventions a cc-style driver's linker follows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkerFlavor {
+    /// GNU ld, gold, lld and mold
+    Gnu,
+    /// ld64, which has no `--whole-archive` or `-Bstatic`
+    Apple,
+    Msvc,
+}
+
+/// Link arguments for a cc-style driver, to go after the objects: search
+/// dirs, rpaths, whole archives, then the libraries in their given order
+pub fn library_args(libraries: &Libraries, flavor: LinkerFlavor) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    if flavor == LinkerFlavor::Msvc
+        && (!libraries.link_whole.is_empty()
+            || libraries
+                .libs
+                .iter()
+                .any(|library| library.prefer() != LinkPreference::Default))
+    {
+        bail!(error!(
+            "link_whole and prefer aren't supported with MSVC, pass /WHOLEARCHIVE: in ldflags instead"
+        ));
+    }
+    for dir in &libraries.lib_dirs {
+        args.push(format!("-L{}", dir.display()));
+    }
+    for rpath in &libraries.rpath {
+        args.push(format!("-Wl,-rpath,{}", rpath));
+    }
+    if flavor == LinkerFlavor::Apple {
+        // ld64 loads whole archives one at a time, by path
+        for library in &libraries.link_whole {
+            let archive = static_archive(library, libraries)?;
+            args.push(format!("-Wl,-force_load,{}", archive.display()));
+        }
+    } else if !libraries.link_whole.is_empty() {
+        args.push("-Wl,--whole-archive".to_owned());
+        for library in &libraries.link_whole {
+            args.extend(library_arg(library, libraries, flavor)?);
+        }
+        args.push("-Wl,--no-whole-archive".to_owned());
+    }
+    for library in &libraries.libs {
+        args.extend(library_arg(library, libraries, flavor)?);
+    }
+    args.extend(libraries.dependency_args.iter().cloned());
+    Ok(args)
+}
+
+fn library_arg(
+    library: &Library,
+    libraries: &Libraries,
+    flavor: LinkerFlavor,
+) -> anyhow::Result<Vec<String>> {
+    let arg = match library.path() {
+        Some(path) => return Ok(vec![path.display().to_string()]),
+        None => format!("-l{}", library.name()),
+    };
+    Ok(match (flavor, library.prefer()) {
+        (_, LinkPreference::Default) => vec![arg],
+        // ld64 always prefers the dylib, so the archive is passed by path
+        (LinkerFlavor::Apple, LinkPreference::Static) => {
+            vec![static_archive(library, libraries)?.display().to_string()]
+        }
+        (LinkerFlavor::Apple, LinkPreference::Dynamic) => vec![arg],
+        // Switch back so the C runtime and later libraries stay dynamic
+        (_, LinkPreference::Static) => {
+            vec!["-Wl,-Bstatic".to_owned(), arg, "-Wl,-Bdynamic".to_owned()]
+        }
+        (_, LinkPreference::Dynamic) => vec!["-Wl,-Bdynamic".to_owned(), arg],
+    })
+}
+
+/// The archive of `library`, given by path or found as `lib<name>.a` in the
+/// library dirs
+fn static_archive(library: &Library, libraries: &Libraries) -> anyhow::Result<PathBuf> {
+    if let Some(path) = library.path() {
+        return Ok(path.to_path_buf());
+    }
+    let file = format!("lib{}.a", library.name());
+    match libraries
+        .lib_dirs
+        .iter()
+        .map(|dir| dir.join(&file))
+        .find(|path| path.is_file())
+    {
+        Some(path) => Ok(path),
+        None => bail!(error!(
+            "Could not find {} in lib_dirs, Apple's linker needs static libraries by path",
+            file
+        )),
+    }
+}
+
+/// Accepts `11`, `c11`, `gnu11`, `17`, `c++17` or `gnu++17`
+fn normalize_std(std: &str, prefix: &str) -> String {
+    if std.chars().all(|c| c.is_ascii_digit()) {
+        format!("{}{}", prefix, std)
+    } else {
+        std.to_owned()
+    }
+}
+
+/// A probed compiler or tool
+#[derive(Debug, Clone)]
+pub struct Tool {
+    pub program: String,
+    pub family: Family,
+    /// e.g. "13.2.0", from the predefined macros when available
+    pub version: Option<String>,
+    /// First line of `--version`, identifies the exact build of the tool
+    pub banner: Option<String>,
+}
+
+/// The configured compilers, probed on first use
+pub struct Toolchain {
+    pub compilers: Compilers,
+    /// Target triple when cross compiling with `--target`
+    pub target: Option<String>,
+    pub sysroot: Option<PathBuf>,
+    pub linker_script: Option<PathBuf>,
+    probed: RefCell<HashMap<String, Tool>>,
+    launcher: OnceCell<Option<String>>,
+}
+
+/// Cumulative hit/miss counters reported by a compiler launcher
+#[derive(Debug, Clone, Copy)]
+pub struct LauncherStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Toolchain {
+    pub fn new(compilers: Compilers) -> Self {
+        Self {
+            compilers,
+            target: None,
+            sysroot: None,
+            linker_script: None,
+            probed: RefCell::new(HashMap::new()),
+            launcher: OnceCell::new(),
+        }
+    }
+
+    /// The compiler launcher, if one is configured and installed
+    pub fn launcher(&self) -> Option<&str> {
+        self.launcher
+            .get_or_init(|| {
+                let configured = match env::var("CBT_COMPILER_LAUNCHER") {
+                    Ok(launcher) => Some(launcher),
+                    Err(_) => self.compilers.launcher.clone(),
+                };
+                match configured.as_deref() {
+                    None | Some("") => None,
+                    Some("auto") => ["ccache", "sccache"]
+                        .into_iter()
+                        .find(|launcher| version_banner(launcher).is_ok())
+                        .map(|launcher| launcher.to_owned()),
+                    Some(launcher) => match version_banner(launcher) {
+                        Ok(_) => Some(launcher.to_owned()),
+                        Err(_) => {
+                            println!(
+                                "{}: compiler launcher {} is not installed, building without it",
+                                warning!("Warning"),
+                                launcher
+                            );
+                            None
+                        }
+                    },
+                }
+            })
+            .as_deref()
+    }
+
+    /// A command for `compiler`, run through the launcher if there is one
+    pub fn compile_command(&self, compiler: &str) -> Command {
+        match self.launcher() {
+            Some(launcher) => {
+                let mut cmd = Command::new(launcher);
+                cmd.arg(compiler);
+                cmd
+            }
+            None => Command::new(compiler),
+        }
+    }
+
+    /// Reads the launcher's hit/miss counters, for ccache (4.x) and sccache
+    pub fn launcher_stats(&self) -> Option<LauncherStats> {
+        let launcher = self.launcher()?;
+        let (args, hits, misses) = stats_query(launcher)?;
+        let output = Command::new(launcher).args(args).output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(parse_stats(
+            &String::from_utf8_lossy(&output.stdout),
+            hits,
+            misses,
+        ))
+    }
+
+    /// Flags that point a cc-style driver at the target and sysroot
+    pub fn target_args(&self, family: Family) -> Vec<String> {
+        let mut args = Vec::new();
+        if family == Family::Msvc {
+            return args;
+        }
+        if let (Family::Clang, Some(target)) = (family, &self.target) {
+            args.push(format!("--target={}", target));
+        }
+        if let Some(sysroot) = &self.sysroot {
+            args.push(format!("--sysroot={}", sysroot.display()));
+        }
+        args
+    }
+
+    /// The nasm/yasm output format for the target, or for the host
+    pub fn object_format(&self) -> &'static str {
+        let host = format!("{}-{}", env::consts::ARCH, env::consts::OS);
+        let triple = self.target.as_deref().unwrap_or(&host);
+        let x86_32 = ["i386", "i486", "i586", "i686", "x86-"]
+            .iter()
+            .any(|arch| triple.starts_with(arch));
+        if triple.contains("windows") || triple.contains("mingw") || triple.contains("cygwin") {
+            if x86_32 {
+                "win32"
+            } else {
+                "win64"
+            }
+        } else if triple.contains("darwin") || triple.contains("apple") || triple.contains("macos")
+        {
+            "macho64"
+        } else if triple.ends_with("gnux32") {
+            "elfx32"
+        } else if x86_32 {
+            "elf32"
+        } else {
+            "elf64"
+        }
+    }
+
+    /// `so`, `dylib` or `dll`, for the target or for the host
+    pub fn shared_library_extension(&self) -> &'static str {
+        let host = format!("{}-{}", env::consts::ARCH, env::consts::OS);
+        let triple = self.target.as_deref().unwrap_or(&host);
+        if triple.contains("windows") || triple.contains("mingw") || triple.contains("cygwin") {
+            "dll"
+        } else if triple.contains("darwin") || triple.contains("apple") || triple.contains("macos")
+        {
+            "dylib"
+        } else {
+            "so"
+        }
+    }
+
+    /// The linker conventions of a `family` driver for the target
+    pub fn linker_flavor(&self, family: Family) -> LinkerFlavor {
+        if family == Family::Msvc {
+            LinkerFlavor::Msvc
+        } else if self.shared_library_extension() == "dylib" {
+            LinkerFlavor::Apple
+        } else {
+            LinkerFlavor::Gnu
+        }
+    }
+
+    /// Probes `program`, failing with a hint at `role` (the `[compilers]`
+    /// key) if it can't be run
+    pub fn tool(&self, program: &str, role: &str) -> anyhow::Result<Tool> {
+        if let Some(tool) = self.probed.borrow().get(program) {
+            return Ok(tool.clone());
+        }
+        let tool = probe(program).with_context(|| {
+            let section = match &self.target {
+                Some(target) => format!("[target.{}]", target),
+                None => "[compilers]".to_owned(),
+            };
+            let env = match (&self.target, role) {
+                (None, "cc") => " or the CC environment variable",
+                (None, "cxx") => " or the CXX environment variable",
+                _ => "",
+            };
+            error!(
+                "Could not find {} ({}). Install it, or set `{}` under {} in cbt.toml{}",
+                program, role, role, section, env
+            )
+        })?;
+        self.probed
+            .borrow_mut()
+            .insert(program.to_owned(), tool.clone());
+        Ok(tool)
+    }
+}
+
+impl std::fmt::Display for Family {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Family::Gcc => write!(f, "GCC"),
+            Family::Clang => write!(f, "Clang"),
+            Family::Msvc => write!(f, "MSVC"),
+            Family::Other => write!(f, "unknown"),
+        }
+    }
+}
+
+/// The first line `program --version` prints, failing if it isn't installed
+fn version_banner(program: &str) -> anyhow::Result<Option<String>> {
+    let output = match Command::new(program)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == ErrorKind::NotFound => bail!("{} is not installed", program),
+        Err(e) => return Err(e).with_context(|| format!("Failed to run {}", program)),
+    };
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .chain(String::from_utf8_lossy(&output.stderr).lines())
+        .map(|line| line.trim().to_owned())
+        .find(|line| !line.is_empty()))
+}
+
+/// The arguments that print a launcher's stats, then the counters that add
+/// up to its hits and to its misses
+type StatsQuery = (
+    &'static [&'static str],
+    &'static [&'static str],
+    &'static [&'static str],
+);
+
+fn stats_query(launcher: &str) -> Option<StatsQuery> {
+    let name = Path::new(launcher).file_stem()?.to_str()?;
+    if name.contains("sccache") {
+        Some((&["--show-stats"], &["Cache hits"], &["Cache misses"]))
+    } else if name.contains("ccache") {
+        Some((
+            &["--print-stats"],
+            &["direct_cache_hit", "preprocessed_cache_hit"],
+            &["cache_miss"],
+        ))
+    } else {
+        None
+    }
+}
+
+/// Sums the `<name> <count>` lines of a stats listing
+fn parse_stats(output: &str, hits: &[&str], misses: &[&str]) -> LauncherStats {
+    let counter = |names: &[&str]| -> u64 {
+        output
+            .lines()
+            .filter_map(|line| {
+                let (name, value) = line.trim().rsplit_once(char::is_whitespace)?;
+                if names.contains(&name.trim()) {
+                    value.parse::<u64>().ok()
+                } else {
+                    None
+                }
+            })
+            .sum()
+    };
+    LauncherStats {
+        hits: counter(hits),
+        misses: counter(misses),
+    }
+}
+
+/// Runs `program --version`, then asks it for its predefined macros to tell
+/// GCC and Clang apart, since `cc`, `c++` and friends may be either
+fn probe(program: &str) -> anyhow::Result<Tool> {
+    let mut tool = Tool {
+        program: program.to_owned(),
+        family: Family::from_program(program),
+        version: None,
+        banner: version_banner(program)?,
+    };
+
+    let macros = Command::new(program)
+        .args(["-dM", "-E", "-x", "c", "-"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    if let Ok(macros) = macros {
+        if macros.status.success() {
+            let macros = parse_macros(&String::from_utf8_lossy(&macros.stdout));
+            let version = |major: &str, minor: &str, patch: &str| {
+                Some(format!(
+                    "{}.{}.{}",
+                    macros.get(major)?,
+                    macros.get(minor)?,
+                    macros.get(patch)?
+                ))
+            };
+            if macros.contains_key("__clang__") {
+                tool.family = Family::Clang;
+                tool.version =
+                    version("__clang_major__", "__clang_minor__", "__clang_patchlevel__");
+            } else if macros.contains_key("__GNUC__") {
+                tool.family = Family::Gcc;
+                tool.version = version("__GNUC__", "__GNUC_MINOR__", "__GNUC_PATCHLEVEL__");
+            }
+        }
+    }
+    Ok(tool)
+}
+
+fn parse_macros(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.strip_prefix("#define ")?.splitn(2, ' ');
+            let name = parts.next()?.to_owned();
+            Some((name, parts.next().unwrap_or("").to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn family_from_known_program_names() {
+        for (program, family) in [
+            ("gcc", Family::Gcc),
+            ("/usr/bin/g++", Family::Gcc),
+            ("cc", Family::Gcc),
+            ("c++", Family::Gcc),
+            ("x86_64-linux-gnu-gcc-13", Family::Gcc),
+            ("arm-none-eabi-g++", Family::Gcc),
+            ("clang", Family::Clang),
+            ("clang++-17", Family::Clang),
+            ("aarch64-linux-gnu-clang", Family::Clang),
+            ("cl.exe", Family::Msvc),
+            ("clang-cl", Family::Msvc),
+            ("icc", Family::Other),
+            ("tcc", Family::Other),
+            ("distcc", Family::Other),
+        ] {
+            assert_eq!(Family::from_program(program), family, "{}", program);
+        }
+    }
+
+    #[test]
+    fn object_format_per_target() {
+        let mut toolchain = Toolchain::new(Compilers::default());
+        for (triple, format) in [
+            ("x86_64-linux-gnu", "elf64"),
+            ("i686-linux-gnu", "elf32"),
+            ("x86_64-linux-gnux32", "elfx32"),
+            ("x86_64-pc-windows-msvc", "win64"),
+            ("i686-w64-mingw32", "win32"),
+            ("x86_64-apple-darwin", "macho64"),
+        ] {
+            toolchain.target = Some(triple.to_owned());
+            assert_eq!(toolchain.object_format(), format, "{}", triple);
+        }
+    }
+
+    #[test]
+    fn launcher_stats_from_captured_output() {
+        let ccache = "stats_updated_timestamp\t1718000000\n\
+                      cache_miss\t7\n\
+                      direct_cache_hit\t12\n\
+                      direct_cache_miss\t9\n\
+                      preprocessed_cache_hit\t2\n\
+                      preprocessed_cache_miss\t7\n\
+                      files_in_cache\t64\n";
+        let (args, hits, misses) = stats_query("/usr/bin/ccache").unwrap();
+        assert_eq!(args, ["--print-stats"]);
+        let stats = parse_stats(ccache, hits, misses);
+        assert_eq!((stats.hits, stats.misses), (14, 7));
+
+        let sccache = r#"Compile requests                     30
+Compile requests executed            21
+Cache hits                           15
+Cache hits (C/C++)                   15
+Cache misses                          6
+Cache misses (C/C++)                  6
+Cache timeouts                        0
+Cache hits rate                   71.43 %
+Cache hits rate (C/C++)           71.43 %
+Cache location                  Local disk: "/home/user/.cache/sccache"
+"#;
+        let (args, hits, misses) = stats_query("sccache").unwrap();
+        assert_eq!(args, ["--show-stats"]);
+        let stats = parse_stats(sccache, hits, misses);
+        assert_eq!((stats.hits, stats.misses), (15, 6));
+
+        assert!(stats_query("distcc").is_none());
+    }
+
+    #[test]
+    fn library_args_order_and_bracketing() {
+        let libraries: Libraries = toml::from_str(
+            r#"
+            lib_dirs = ["vendor"]
+            rpath = ["$ORIGIN/../lib"]
+            link_whole = ["plugins", "vendor/libextra.a"]
+            libs = ["foo", { name = "z", prefer = "static" }, { name = "ssl", prefer = "dynamic" }, "m"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            library_args(&libraries, LinkerFlavor::Gnu).unwrap(),
+            [
+                "-Lvendor",
+                "-Wl,-rpath,$ORIGIN/../lib",
+                "-Wl,--whole-archive",
+                "-lplugins",
+                "vendor/libextra.a",
+                "-Wl,--no-whole-archive",
+                "-lfoo",
+                "-Wl,-Bstatic",
+                "-lz",
+                "-Wl,-Bdynamic",
+                "-Wl,-Bdynamic",
+                "-lssl",
+                "-lm",
+            ]
+        );
+        assert!(library_args(&libraries, LinkerFlavor::Msvc).is_err());
+        assert!(library_args(&Libraries::default(), LinkerFlavor::Msvc)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn apple_libraries_by_path() {
+        let dir = env::temp_dir().join(format!("cbt-libs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("libplugins.a"), "").unwrap();
+        std::fs::write(dir.join("libz.a"), "").unwrap();
+        let mut libraries = Libraries {
+            lib_dirs: vec![dir.clone()],
+            link_whole: vec![Library::Name("plugins".to_owned())],
+            libs: vec![
+                Library::Detailed {
+                    name: "z".to_owned(),
+                    prefer: LinkPreference::Static,
+                },
+                Library::Name("m".to_owned()),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            library_args(&libraries, LinkerFlavor::Apple).unwrap(),
+            [
+                format!("-L{}", dir.display()),
+                format!("-Wl,-force_load,{}", dir.join("libplugins.a").display()),
+                dir.join("libz.a").display().to_string(),
+                "-lm".to_owned(),
+            ]
+        );
+        libraries.link_whole = vec![Library::Name("missing".to_owned())];
+        assert!(library_args(&libraries, LinkerFlavor::Apple).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn settings_flags_per_family() {
+        let settings: Settings = toml::from_str(
+            r#"
+            c_std = "11"
+            cxx_std = "gnu++17"
+            opt_level = "s"
+            debug_info = true
+            warnings = "extra"
+            warnings_as_errors = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            settings_flags(Family::Gcc, Language::C, &settings),
+            ["-std=c11", "-Os", "-g", "-Wall", "-Wextra", "-Werror"]
+        );
+        assert_eq!(
+            settings_flags(Family::Clang, Language::Cxx, &settings),
+            ["-std=gnu++17", "-Os", "-g", "-Wall", "-Wextra", "-Werror"]
+        );
+        assert_eq!(
+            settings_flags(Family::Msvc, Language::C, &settings),
+            ["/std:c11", "/O1", "/Zi", "/W4", "/WX"]
+        );
+        assert_eq!(
+            settings_flags(Family::Gcc, Language::AsmCpp, &Settings::default()),
+            Vec::<String>::new()
+        );
+    }
+}
@@ -1,7 +1,11 @@
-use std::process::Output;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Output,
+};
 
 use crate::error;
-use anyhow::bail;
+use anyhow::{bail, Context};
 
 pub fn process_output(
     output: Output,
@@ -15,3 +19,67 @@ pub fn process_output(
         bail!(error!("{process} failed to {action} {filename}"))
     }
 }
+
+/// Reads the prerequisites out of a make-style dependency file as written by
+/// `cc -MD`, `nasm -MD`, `as --MD` or `yasm -M`
+pub fn read_depfile(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| error!("Could not read dependency file {}", path.display()))?;
+    let mut deps = Vec::new();
+    for rule in contents.replace("\\\n", " ").lines() {
+        // Split on the first ": " so Windows drive letters are left alone
+        let prereqs = match rule.split_once(": ") {
+            Some((_, prereqs)) => prereqs,
+            None => continue,
+        };
+        let mut current = String::new();
+        let mut chars = prereqs.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&' ') => {
+                    current.push(' ');
+                    chars.next();
+                }
+                ' ' | '\t' => {
+                    if !current.is_empty() {
+                        deps.push(PathBuf::from(std::mem::take(&mut current)));
+                    }
+                }
+                _ => current.push(c),
+            }
+        }
+        if !current.is_empty() {
+            deps.push(PathBuf::from(current));
+        }
+    }
+    Ok(deps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Vec<PathBuf> {
+        let path = std::env::temp_dir().join(format!("cbt-depfile-{}.d", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let deps = read_depfile(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        deps
+    }
+
+    #[test]
+    fn depfile_continuations_and_escaped_spaces() {
+        assert_eq!(
+            parse("obj/a.o: src/a.c \\\n  include/my\\ header.h\tb.h\n"),
+            ["src/a.c", "include/my header.h", "b.h"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn depfile_drive_letters_and_phony_targets() {
+        assert_eq!(
+            parse("C:/obj/a.o: C:/src/a.c C:/inc/a.h\n\nC:/inc/a.h:\n"),
+            ["C:/src/a.c", "C:/inc/a.h"].map(PathBuf::from)
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+/// A copy of the repository's example project, so builds don't touch the
+/// checkout
+fn example_project(name: &str) -> PathBuf {
+    let repo = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let dir = env::temp_dir().join(format!("cbt-test-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    copy_dir(
+        &repo.join("example").join("src"),
+        &dir.join("example").join("src"),
+    );
+    fs::copy(repo.join("cbt.toml"), dir.join("cbt.toml")).unwrap();
+    dir
+}
+
+fn cbt(dir: &Path, args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_cbt"))
+        .args(args)
+        .arg("--config")
+        .arg(dir.join("cbt.toml"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "cbt {} failed:\n{}{}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn builds_the_example_project() {
+    let dir = example_project("example");
+    cbt(&dir, &["build"]);
+    let output = Command::new(dir.join("example").join("build").join("test_bin"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello world!!!\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn builds_the_example_project_with_a_relocatable_link() {
+    let dir = example_project("relocatable");
+    let config = fs::read_to_string(dir.join("cbt.toml")).unwrap();
+    fs::write(
+        dir.join("cbt.toml"),
+        config.replace("[stage.build]\n", "[stage.build]\nlink = \"relocatable\"\n"),
+    )
+    .unwrap();
+    cbt(&dir, &["build"]);
+    assert!(dir
+        .join("example")
+        .join("build")
+        .join("test_bin.o")
+        .is_file());
+    let output = Command::new(dir.join("example").join("build").join("test_bin"))
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello world!!!\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The paths in a `cbt dist` tarball
+fn tarball_entries(tarball: &Path) -> Vec<String> {
+    let file = fs::File::open(tarball).unwrap();
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+    archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+        .collect()
+}
+
+#[test]
+fn dist_packs_only_the_sources_reproducibly() {
+    let dir = example_project("dist");
+    let config = fs::read_to_string(dir.join("cbt.toml")).unwrap();
+    fs::write(
+        dir.join("cbt.toml"),
+        format!(
+            "{}\n[project]\nname = \"example\"\nversion = \"1.0.0\"\n",
+            config
+        ),
+    )
+    .unwrap();
+    fs::write(dir.join("notes.txt"), "not listed anywhere").unwrap();
+    fs::create_dir_all(dir.join("example").join("other")).unwrap();
+    fs::write(dir.join("example").join("other").join("old.c"), "").unwrap();
+    // Leaves objects, the executable and .cbt/ behind
+    cbt(&dir, &["build"]);
+    cbt(&dir, &["dist"]);
+
+    let tarball = dir.join("example-1.0.0.tar.gz");
+    assert_eq!(
+        tarball_entries(&tarball),
+        [
+            "example-1.0.0/cbt.toml",
+            "example-1.0.0/example/src/test.c",
+            "example-1.0.0/example/src/test2.c",
+            "example-1.0.0/example/src/test2.h",
+        ]
+    );
+
+    let first = fs::read(&tarball).unwrap();
+    fs::remove_file(&tarball).unwrap();
+    // Same contents, new mtime
+    let source = dir.join("example").join("src").join("test.c");
+    fs::write(&source, fs::read(&source).unwrap()).unwrap();
+    cbt(&dir, &["dist"]);
+    assert!(
+        first == fs::read(&tarball).unwrap(),
+        "dist is not reproducible"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}